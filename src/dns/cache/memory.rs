@@ -0,0 +1,263 @@
+use super::Cache;
+use crate::dns::settings::CacheEviction;
+use async_trait::async_trait;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+};
+use tokio::time::{Duration, Instant};
+
+struct Entry {
+    value: Vec<u8>,
+    expires_at: Instant,
+    // when the entry was last used, unique within its shard
+    tick: u64,
+    hits: u64,
+}
+
+// Entries of one shard, with their eviction order: by last use for LRU,
+// by hits and then last use for LFU.
+struct Shard {
+    entries: HashMap<String, Entry>,
+    order: BTreeMap<(u64, u64), String>,
+    bytes: usize,
+    tick: u64,
+}
+
+// An in-process cache, split into shards that are locked separately,
+// each bounded by its share of the entry count and bytes.
+pub struct MemoryCache {
+    shards: Vec<Mutex<Shard>>,
+    eviction: CacheEviction,
+    max_entries: usize,
+    max_bytes: usize,
+}
+
+impl MemoryCache {
+    pub fn new(
+        shards: usize,
+        max_entries: usize,
+        max_bytes: usize,
+        eviction: CacheEviction,
+    ) -> Self {
+        let shards = shards.max(1);
+        MemoryCache {
+            shards: (0..shards)
+                .map(|_| {
+                    Mutex::new(Shard {
+                        entries: HashMap::new(),
+                        order: BTreeMap::new(),
+                        bytes: 0,
+                        tick: 0,
+                    })
+                })
+                .collect(),
+            eviction,
+            max_entries: (max_entries / shards).max(1),
+            max_bytes: (max_bytes / shards).max(1),
+        }
+    }
+
+    fn get_shard(&self, key: &str) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    fn rank(&self, entry: &Entry) -> (u64, u64) {
+        match self.eviction {
+            CacheEviction::Lru => (0, entry.tick),
+            CacheEviction::Lfu => (entry.hits, entry.tick),
+        }
+    }
+}
+
+impl Shard {
+    fn remove(&mut self, key: &str, rank: (u64, u64)) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&rank);
+        self.bytes -= key.len() + entry.value.len();
+        Some(entry)
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[async_trait]
+impl Cache for MemoryCache {
+    async fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let mut shard = self.get_shard(key).lock().unwrap();
+        let rank = self.rank(shard.entries.get(key)?);
+        let mut entry = shard.remove(key, rank)?;
+        if entry.expires_at <= Instant::now() {
+            return None;
+        }
+
+        entry.tick = shard.next_tick();
+        entry.hits += 1;
+        let value = entry.value.clone();
+        shard.order.insert(self.rank(&entry), key.to_string());
+        shard.bytes += key.len() + entry.value.len();
+        shard.entries.insert(key.to_string(), entry);
+        Some(value)
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>, expire: Duration) {
+        let size = key.len() + value.len();
+        if size > self.max_bytes {
+            return;
+        }
+
+        let mut shard = self.get_shard(key).lock().unwrap();
+        let mut hits = 0;
+        if let Some(entry) = shard.entries.get(key) {
+            let rank = self.rank(entry);
+            hits = shard.remove(key, rank).map(|entry| entry.hits).unwrap_or(0);
+        }
+
+        // expired entries are dropped as they are read, the rest goes by the eviction order
+        while shard.entries.len() >= self.max_entries || shard.bytes + size > self.max_bytes {
+            let victim = shard
+                .order
+                .iter()
+                .next()
+                .map(|(rank, key)| (key.clone(), *rank));
+            match victim {
+                Some((key, rank)) => {
+                    shard.remove(&key, rank);
+                }
+                None => break,
+            }
+        }
+
+        let entry = Entry {
+            value,
+            expires_at: Instant::now() + expire,
+            tick: shard.next_tick(),
+            hits,
+        };
+        shard.order.insert(self.rank(&entry), key.to_string());
+        shard.bytes += size;
+        shard.entries.insert(key.to_string(), entry);
+    }
+
+    async fn delete(&self, key: &str) {
+        let mut shard = self.get_shard(key).lock().unwrap();
+        if let Some(entry) = shard.entries.get(key) {
+            let rank = self.rank(entry);
+            shard.remove(key, rank);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPIRE: Duration = Duration::from_secs(60);
+
+    // one shard, so that the limits apply to all the keys together
+    fn new_cache(max_entries: usize, max_bytes: usize, eviction: CacheEviction) -> MemoryCache {
+        MemoryCache::new(1, max_entries, max_bytes, eviction)
+    }
+
+    #[tokio::test]
+    async fn bounds_entries() {
+        let cache = new_cache(2, 1024, CacheEviction::Lru);
+        cache.set("a", vec![1], EXPIRE).await;
+        cache.set("b", vec![2], EXPIRE).await;
+        cache.set("c", vec![3], EXPIRE).await;
+
+        assert_eq!(cache.get("a").await, None);
+        assert_eq!(cache.get("b").await, Some(vec![2]));
+        assert_eq!(cache.get("c").await, Some(vec![3]));
+    }
+
+    #[tokio::test]
+    async fn bounds_bytes() {
+        // keys count along with values
+        let cache = new_cache(100, 10, CacheEviction::Lru);
+        cache.set("a", vec![0; 5], EXPIRE).await;
+        cache.set("b", vec![0; 5], EXPIRE).await;
+        assert_eq!(cache.get("a").await, None);
+        assert_eq!(cache.get("b").await, Some(vec![0; 5]));
+
+        // values larger than the whole cache are not taken
+        cache.set("c", vec![0; 10], EXPIRE).await;
+        assert_eq!(cache.get("c").await, None);
+        assert_eq!(cache.get("b").await, Some(vec![0; 5]));
+    }
+
+    #[tokio::test]
+    async fn replacing_keeps_bytes() {
+        let cache = new_cache(100, 10, CacheEviction::Lru);
+        cache.set("a", vec![0; 4], EXPIRE).await;
+        cache.set("a", vec![1; 4], EXPIRE).await;
+        cache.set("b", vec![2; 4], EXPIRE).await;
+        assert_eq!(cache.get("a").await, Some(vec![1; 4]));
+        assert_eq!(cache.get("b").await, Some(vec![2; 4]));
+    }
+
+    #[tokio::test]
+    async fn lru_evicts_least_recently_used() {
+        let cache = new_cache(2, 1024, CacheEviction::Lru);
+        cache.set("a", vec![1], EXPIRE).await;
+        cache.set("b", vec![2], EXPIRE).await;
+        cache.get("a").await;
+        cache.get("a").await;
+        cache.get("b").await;
+        cache.set("c", vec![3], EXPIRE).await;
+
+        assert_eq!(cache.get("a").await, None);
+        assert_eq!(cache.get("b").await, Some(vec![2]));
+        assert_eq!(cache.get("c").await, Some(vec![3]));
+    }
+
+    #[tokio::test]
+    async fn lfu_evicts_least_frequently_used() {
+        let cache = new_cache(2, 1024, CacheEviction::Lfu);
+        cache.set("a", vec![1], EXPIRE).await;
+        cache.set("b", vec![2], EXPIRE).await;
+        cache.get("a").await;
+        cache.get("a").await;
+        cache.get("b").await;
+        cache.set("c", vec![3], EXPIRE).await;
+
+        assert_eq!(cache.get("a").await, Some(vec![1]));
+        assert_eq!(cache.get("b").await, None);
+        assert_eq!(cache.get("c").await, Some(vec![3]));
+    }
+
+    #[tokio::test]
+    async fn drops_expired_on_get() {
+        let cache = new_cache(2, 1024, CacheEviction::Lru);
+        cache.set("a", vec![1], Duration::from_secs(0)).await;
+        cache.set("b", vec![2], EXPIRE).await;
+
+        assert_eq!(cache.get("a").await, None);
+        assert_eq!(cache.get("b").await, Some(vec![2]));
+        // the expired entry no longer takes room
+        cache.set("c", vec![3], EXPIRE).await;
+        assert_eq!(cache.get("b").await, Some(vec![2]));
+        assert_eq!(cache.get("c").await, Some(vec![3]));
+    }
+
+    #[tokio::test]
+    async fn deletes_entries() {
+        let cache = new_cache(1, 1024, CacheEviction::Lru);
+        cache.set("a", vec![1], EXPIRE).await;
+        cache.delete("a").await;
+        cache.delete("b").await;
+        assert_eq!(cache.get("a").await, None);
+
+        // the room of the deleted entry is given back
+        cache.set("b", vec![2], EXPIRE).await;
+        cache.set("c", vec![3], EXPIRE).await;
+        assert_eq!(cache.get("b").await, None);
+        assert_eq!(cache.get("c").await, Some(vec![3]));
+    }
+}
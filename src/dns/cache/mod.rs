@@ -0,0 +1,224 @@
+mod memory;
+mod prefetch;
+mod redis;
+
+use self::memory::MemoryCache;
+pub use self::prefetch::Prefetcher;
+use self::redis::RedisCache;
+use super::{
+    ede::{ExtendedError, ExtendedErrorCode},
+    lookup::utils::{is_valid_response, QueryResponse},
+    settings::{CacheBackend, DNSSettings},
+    utils::{
+        age_response, cap_ttls, get_cached_message, get_min_answer_ttl, get_negative_ttl,
+        get_response_message,
+    },
+};
+use async_trait::async_trait;
+use domain::{
+    base::{Dname, Message, Record},
+    rdata::A,
+};
+use std::{
+    convert::TryInto,
+    io::{Error, ErrorKind},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::time::Duration;
+
+// bumped whenever the layout of saved entries changes, so that entries of
+// another layout, e.g. left in redis by an older version, are never read
+const CACHE_VERSION: &str = "v1";
+
+// ttl of expired answers when they are served, as RFC 8767 recommends
+const STALE_ANSWER_TTL: u32 = 30;
+
+pub enum CacheState {
+    // the ttl it was saved with and the seconds left of it
+    Fresh { ttl: u32, remaining: u32 },
+    // expired, only to be served when upstreams fail
+    Stale,
+}
+
+// A store of answers, keyed by `name|type|class`.
+#[async_trait]
+pub trait Cache: Send + Sync {
+    async fn get(&self, key: &str) -> Option<Vec<u8>>;
+    async fn set(&self, key: &str, value: Vec<u8>, expire: Duration);
+    async fn delete(&self, key: &str);
+}
+
+// Answers and negative answers (RFC 2308) are kept in separate stores,
+// so that either kind can be flushed, or fill up, without touching the other.
+pub struct AnswerCache {
+    answers: Box<dyn Cache>,
+    negatives: Box<dyn Cache>,
+}
+
+pub async fn new_cache(settings: &DNSSettings) -> Option<Arc<AnswerCache>> {
+    let cache = match settings.cache_backend()? {
+        CacheBackend::Memory => {
+            let cache = settings.cache.clone().unwrap_or_default();
+            println!(
+                "Using memory as cache, {} entries and {} bytes at most in {} shards, {:?} eviction.",
+                cache.max_entries(),
+                cache.max_bytes(),
+                cache.shards(),
+                cache.eviction()
+            );
+            // negative answers are small and short-lived, a quarter of the room is enough
+            let new_memory = |share: usize| {
+                Box::new(MemoryCache::new(
+                    cache.shards(),
+                    cache.max_entries() / share,
+                    cache.max_bytes() / share,
+                    cache.eviction(),
+                ))
+            };
+            AnswerCache {
+                answers: new_memory(1),
+                negatives: new_memory(4),
+            }
+        }
+        CacheBackend::Redis => match &settings.redis_server {
+            Some(redis_server) => {
+                let redis = RedisCache::new(redis_server).await;
+                AnswerCache {
+                    negatives: Box::new(redis.namespace("negative|")),
+                    answers: Box::new(redis),
+                }
+            }
+            None => panic!("the redis cache requires `redis_server`"),
+        },
+    };
+    Some(Arc::new(cache))
+}
+
+pub async fn lookup_cache(
+    message: &Message<Vec<u8>>,
+    cache: &Option<Arc<AnswerCache>>,
+    identifier: &str,
+) -> Result<(QueryResponse, String, CacheState), Error> {
+    let cache = cache
+        .as_ref()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "[Cache] Not found"))?;
+    let key = get_cache_key(identifier);
+    let (mut buf, is_negative) = match cache.answers.get(&key).await {
+        Some(buf) => (buf, false),
+        None => match cache.negatives.get(&key).await {
+            Some(buf) => (buf, true),
+            None => return Err(Error::new(ErrorKind::NotFound, "[Cache] Not found")),
+        },
+    };
+
+    // the group name which answered is saved after the message, followed by its length,
+    // and the time it was saved with its ttl before them
+    let invalid = || Error::new(ErrorKind::InvalidData, "[Cache] Invalid data");
+    let group_len = buf.pop().ok_or_else(invalid)? as usize;
+    if group_len + 12 + 12 > buf.len() {
+        return Err(invalid());
+    }
+    let group = buf.split_off(buf.len() - group_len);
+    let group = String::from_utf8_lossy(&group).to_string();
+    let ttl = buf.split_off(buf.len() - 4);
+    let ttl = u32::from_be_bytes(ttl.as_slice().try_into().unwrap());
+    let saved_at = buf.split_off(buf.len() - 8);
+    let saved_at = u64::from_be_bytes(saved_at.as_slice().try_into().unwrap());
+
+    let elapsed = get_timestamp().saturating_sub(saved_at);
+    if elapsed >= ttl as u64 {
+        cap_ttls(&mut buf, STALE_ANSWER_TTL);
+        let saved_message = get_saved_message(buf).ok_or_else(invalid)?;
+        let error = ExtendedError::new(ExtendedErrorCode::StaleAnswer, "upstreams failed");
+        let ret_message = get_cached_message(message, &saved_message, Some(error));
+        return Ok((QueryResponse::Cache(ret_message), group, CacheState::Stale));
+    }
+
+    // ttls are served as they are counted down by now
+    let elapsed = elapsed as u32;
+    age_response(&mut buf, elapsed);
+    let saved_message = get_saved_message(buf).ok_or_else(invalid)?;
+    // negative answers keep their rcode and SOA
+    let ret_message = if is_negative {
+        get_cached_message(message, &saved_message, None)
+    } else {
+        get_response_message::<Record<Dname<Vec<u8>>, A>>(
+            message.header().id(),
+            &saved_message,
+            None,
+        )
+    };
+
+    let state = CacheState::Fresh {
+        ttl,
+        remaining: ttl - elapsed,
+    };
+    Ok((QueryResponse::Cache(ret_message), group, state))
+}
+
+// answers are kept as long as their lowest ttl, negative ones as long as their SOA says
+pub async fn save_cache(
+    cache: &Option<Arc<AnswerCache>>,
+    identifier: &str,
+    buf: &[u8],
+    group: &str,
+    settings: &DNSSettings,
+) {
+    let cache = match cache {
+        Some(cache) => cache,
+        None => return,
+    };
+    let (store, other, ttl) = if let Some(ttl) = get_negative_ttl(buf) {
+        let ttl = ttl.min(settings.cache_negative_max_ttl());
+        (&cache.negatives, &cache.answers, ttl)
+    } else if let Some(ttl) = get_min_answer_ttl(buf) {
+        let ttl = ttl
+            .max(settings.cache_min_ttl())
+            .min(settings.cache_max_ttl());
+        (&cache.answers, &cache.negatives, ttl)
+    } else {
+        // negative answers without SOA are not cached (RFC 2308 5)
+        return;
+    };
+    // an answer of the other kind saved before is outdated by this one,
+    // e.g. a stale address must not shadow the NXDOMAIN which followed it
+    let key = get_cache_key(identifier);
+    other.delete(&key).await;
+    if ttl == 0 {
+        return;
+    }
+
+    let mut cache_buf = buf.to_vec();
+    cache_buf.extend_from_slice(&get_timestamp().to_be_bytes());
+    cache_buf.extend_from_slice(&ttl.to_be_bytes());
+    cache_buf.extend_from_slice(group.as_bytes());
+    cache_buf.push(group.len() as u8);
+    // expired answers are kept a while longer for serve-stale
+    let expire = ttl as u64 + settings.cache_serve_stale() as u64;
+    store
+        .set(&key, cache_buf, Duration::from_secs(expire))
+        .await;
+}
+
+fn get_cache_key(identifier: &str) -> String {
+    format!("{}|{}", CACHE_VERSION, identifier)
+}
+
+// the saved message, if every record of it can be served again
+fn get_saved_message(buf: Vec<u8>) -> Option<Message<Vec<u8>>> {
+    let message = Message::from_octets(buf).ok()?;
+    if is_valid_response(&message) {
+        Some(message)
+    } else {
+        None
+    }
+}
+
+// seconds since the unix epoch, which holds across restarts and instances sharing redis
+fn get_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
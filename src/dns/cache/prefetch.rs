@@ -0,0 +1,86 @@
+use crate::dns::settings::DNSSettings;
+use std::{collections::HashMap, sync::Mutex};
+use tokio::time::{Duration, Instant};
+
+// answers followed at once, more are not counted until some expire
+const MAX_TRACKED: usize = 65536;
+
+struct Tracked {
+    expires_at: Instant,
+    hits: u32,
+    refreshing: bool,
+}
+
+// Counts the hits of cached answers, to refresh the popular ones before they expire,
+// and keeps to one refresh at a time for each answer, whatever started it.
+pub struct Prefetcher {
+    min_hits: Option<u32>,
+    tracked: Mutex<HashMap<String, Tracked>>,
+}
+
+impl Prefetcher {
+    pub fn new(settings: &DNSSettings) -> Self {
+        Prefetcher {
+            min_hits: settings.cache_prefetch_hits(),
+            tracked: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // count a hit of a cached answer, whether it should be refreshed now,
+    // that is in the last tenth of its ttl with enough hits and no refresh running
+    pub fn hit(&self, key: &str, ttl: u32, remaining: u32) -> bool {
+        let min_hits = match self.min_hits {
+            Some(min_hits) => min_hits,
+            None => return false,
+        };
+
+        let now = Instant::now();
+        let mut tracked = self.tracked.lock().unwrap();
+        if tracked.len() >= MAX_TRACKED && !tracked.contains_key(key) {
+            tracked.retain(|_, state| state.expires_at > now || state.refreshing);
+            if tracked.len() >= MAX_TRACKED {
+                return false;
+            }
+        }
+
+        let expires_at = now + Duration::from_secs(remaining as u64);
+        let state = tracked.entry(key.to_string()).or_insert(Tracked {
+            expires_at,
+            hits: 0,
+            refreshing: false,
+        });
+        // the answer was saved again since
+        if state.expires_at <= now && !state.refreshing {
+            state.hits = 0;
+        }
+        state.expires_at = expires_at;
+        state.hits += 1;
+
+        if !state.refreshing && state.hits >= min_hits && remaining * 10 <= ttl {
+            state.refreshing = true;
+            true
+        } else {
+            false
+        }
+    }
+
+    // claim the refresh of an answer, false if one is running already
+    pub fn start(&self, key: &str) -> bool {
+        let mut tracked = self.tracked.lock().unwrap();
+        let state = tracked.entry(key.to_string()).or_insert(Tracked {
+            expires_at: Instant::now(),
+            hits: 0,
+            refreshing: false,
+        });
+        if state.refreshing {
+            return false;
+        }
+        state.refreshing = true;
+        true
+    }
+
+    // the refreshed answer starts counting again
+    pub fn finish(&self, key: &str) {
+        self.tracked.lock().unwrap().remove(key);
+    }
+}
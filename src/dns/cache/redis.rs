@@ -0,0 +1,73 @@
+use super::Cache;
+use async_trait::async_trait;
+use redis::{aio::Connection, AsyncCommands};
+use std::sync::Arc;
+use tokio::{sync::Mutex, time::Duration};
+
+pub struct RedisCache {
+    connection: Arc<Mutex<Connection>>,
+    // put before every key, to keep kinds of entries apart
+    prefix: String,
+}
+
+impl RedisCache {
+    pub async fn new(redis_server: &str) -> Self {
+        let client = redis::Client::open(redis_server).unwrap();
+        match client.get_async_connection().await {
+            Ok(connection) => {
+                println!("Using redis at {} as cache.", redis_server);
+                RedisCache {
+                    connection: Arc::new(Mutex::new(connection)),
+                    prefix: String::new(),
+                }
+            }
+            Err(e) => {
+                panic!("error on redis instance: {}", e);
+            }
+        }
+    }
+
+    // the same connection, with keys under `prefix`
+    pub fn namespace(&self, prefix: &str) -> Self {
+        RedisCache {
+            connection: self.connection.clone(),
+            prefix: prefix.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl Cache for RedisCache {
+    async fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let key = format!("{}{}", self.prefix, key);
+        let mut connection = self.connection.lock().await;
+        match connection.get::<String, Vec<u8>>(key).await {
+            Ok(buf) if !buf.is_empty() => Some(buf),
+            Ok(_) => None,
+            Err(err) => {
+                println!("Failed to read data from cache ({}), ignored.", err);
+                None
+            }
+        }
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>, expire: Duration) {
+        let key = format!("{}{}", self.prefix, key);
+        let mut connection = self.connection.lock().await;
+        let expire = expire.as_secs().max(1) as usize;
+        if let Err(err) = connection
+            .set_ex::<String, Vec<u8>, String>(key, value, expire)
+            .await
+        {
+            println!("Failed to save data to cache ({}), ignored.", err);
+        }
+    }
+
+    async fn delete(&self, key: &str) {
+        let key = format!("{}{}", self.prefix, key);
+        let mut connection = self.connection.lock().await;
+        if let Err(err) = connection.del::<String, usize>(key).await {
+            println!("Failed to delete data from cache ({}), ignored.", err);
+        }
+    }
+}
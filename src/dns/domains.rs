@@ -0,0 +1,230 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DomainListFormat {
+    // `server=/example.com/114.114.114.114` lines, like dnsmasq-china-list
+    Dnsmasq,
+    // one domain per line
+    Plain,
+    // adblock rules like gfwlist, base64 encoded or not
+    Gfwlist,
+}
+
+// Maps domains to values, a domain also covers all of its subdomains.
+// Labels are stored from the top level down, so a lookup takes one step per label
+// however many domains there are.
+#[derive(Clone, Default)]
+pub struct DomainTrie {
+    root: TrieNode,
+    size: usize,
+}
+
+#[derive(Clone, Default)]
+struct TrieNode {
+    children: HashMap<Box<str>, TrieNode>,
+    value: Option<usize>,
+}
+
+impl DomainTrie {
+    // a domain inserted before keeps its value
+    pub fn insert(&mut self, domain: &str, value: usize) {
+        let domain = normalize(domain);
+        if domain.is_empty() {
+            return;
+        }
+        let mut node = &mut self.root;
+        for label in domain.rsplit('.') {
+            node = node.children.entry(label.into()).or_default();
+        }
+        if node.value.is_none() {
+            node.value = Some(value);
+            self.size += 1;
+        }
+    }
+
+    // the value of the longest listed suffix of the domain
+    pub fn lookup(&self, domain: &str) -> Option<usize> {
+        let domain = normalize(domain);
+        let mut node = &self.root;
+        let mut value = None;
+        for label in domain.rsplit('.') {
+            node = match node.children.get(label) {
+                Some(child) => child,
+                None => break,
+            };
+            if node.value.is_some() {
+                value = node.value;
+            }
+        }
+        value
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+}
+
+impl fmt::Debug for DomainTrie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DomainTrie({} domains)", self.size)
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain
+        .trim()
+        .trim_start_matches("*.")
+        .trim_matches('.')
+        .to_ascii_lowercase()
+}
+
+pub fn parse_domain_list(text: &str, format: DomainListFormat) -> Vec<String> {
+    match format {
+        DomainListFormat::Dnsmasq => parse_dnsmasq(text),
+        DomainListFormat::Plain => parse_plain(text),
+        DomainListFormat::Gfwlist => {
+            let compact: String = text.split_whitespace().collect();
+            match base64::decode(&compact) {
+                Ok(decoded) => parse_adblock(&String::from_utf8_lossy(&decoded)),
+                Err(_) => parse_adblock(text),
+            }
+        }
+    }
+}
+
+fn parse_dnsmasq(text: &str) -> Vec<String> {
+    let mut domains = vec![];
+    for line in text.lines() {
+        let line = line.trim();
+        // `server=/a.com/b.com/1.2.3.4`, the last part is the server
+        if let Some(rest) = line.strip_prefix("server=/") {
+            let mut parts: Vec<&str> = rest.split('/').collect();
+            parts.pop();
+            for domain in parts {
+                if is_domain(domain) {
+                    domains.push(domain.to_string());
+                }
+            }
+        }
+    }
+    domains
+}
+
+fn parse_plain(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| is_domain(line))
+        .map(|line| line.to_string())
+        .collect()
+}
+
+// only rules that name a whole domain are taken, exceptions, regexes and wildcards are skipped
+fn parse_adblock(text: &str) -> Vec<String> {
+    let mut domains = vec![];
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+            continue;
+        }
+        if line.starts_with("@@") || line.starts_with('/') {
+            continue;
+        }
+
+        let rule = line.trim_start_matches('|');
+        let rule = rule
+            .strip_prefix("http://")
+            .or_else(|| rule.strip_prefix("https://"))
+            .unwrap_or(rule);
+        let end = rule.find(&['/', ':', '^'][..]).unwrap_or(rule.len());
+        let domain = rule[..end].trim_start_matches('.');
+        if is_domain(domain) {
+            domains.push(domain.to_string());
+        }
+    }
+    domains
+}
+
+fn is_domain(domain: &str) -> bool {
+    let domain = domain.trim_start_matches("*.");
+    !domain.is_empty()
+        && domain.contains('.')
+        && domain
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_matches_longest_suffix() {
+        let mut trie = DomainTrie::default();
+        trie.insert("example.com", 0);
+        trie.insert("cdn.example.com.", 1);
+        trie.insert("*.Example.org", 2);
+
+        assert_eq!(trie.lookup("example.com"), Some(0));
+        assert_eq!(trie.lookup("www.example.com"), Some(0));
+        assert_eq!(trie.lookup("a.cdn.example.com"), Some(1));
+        assert_eq!(trie.lookup("WWW.EXAMPLE.ORG."), Some(2));
+        // a suffix must end at a label boundary
+        assert_eq!(trie.lookup("badexample.com"), None);
+        assert_eq!(trie.lookup("com"), None);
+        assert_eq!(trie.len(), 3);
+    }
+
+    #[test]
+    fn insert_keeps_first_value() {
+        let mut trie = DomainTrie::default();
+        trie.insert("example.com", 0);
+        trie.insert("example.com", 1);
+        assert_eq!(trie.lookup("example.com"), Some(0));
+        assert_eq!(trie.len(), 1);
+    }
+
+    #[test]
+    fn parse_dnsmasq_list() {
+        let text = "# comment\n\
+                    server=/baidu.com/114.114.114.114\n\
+                    server=/qq.com/weixin.qq.com/119.29.29.29\n\
+                    address=/ads.com/0.0.0.0\n";
+        assert_eq!(
+            parse_domain_list(text, DomainListFormat::Dnsmasq),
+            vec!["baidu.com", "qq.com", "weixin.qq.com"]
+        );
+    }
+
+    #[test]
+    fn parse_plain_list() {
+        let text = "example.com\n  # comment\nexample.org # trailing\n\nlocalhost\n";
+        assert_eq!(
+            parse_domain_list(text, DomainListFormat::Plain),
+            vec!["example.com", "example.org"]
+        );
+    }
+
+    #[test]
+    fn parse_gfwlist() {
+        let text = "[AutoProxy 0.2.9]\n\
+                    ! comment\n\
+                    ||google.com\n\
+                    |https://www.example.com/path\n\
+                    .twitter.com\n\
+                    @@||cn.example.com\n\
+                    /^https?:\\/\\/[^\\/]+blogspot\\.(.*)/\n";
+        let expected = vec!["google.com", "www.example.com", "twitter.com"];
+        assert_eq!(parse_domain_list(text, DomainListFormat::Gfwlist), expected);
+
+        // the published list is base64 encoded, wrapped in lines
+        let encoded = base64::encode(text);
+        let (head, tail) = encoded.split_at(encoded.len() / 2);
+        let wrapped = format!("{}\n{}\n", head, tail);
+        assert_eq!(
+            parse_domain_list(&wrapped, DomainListFormat::Gfwlist),
+            expected
+        );
+    }
+}
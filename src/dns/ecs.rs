@@ -0,0 +1,247 @@
+use super::settings::{DNSSettings, EcsPolicy};
+use domain::{
+    base::{iana::Rtype, opt::ClientSubnet, Dname, Message, MessageBuilder},
+    rdata::AllRecordData,
+};
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, RwLock},
+};
+use tokio::{
+    net::UdpSocket,
+    time::{sleep, timeout, Duration},
+};
+
+// answered with the source address of the query when asked to google's authoritative servers
+const MYADDR_NAME: &str = "o-o.myaddr.l.google.com";
+// ns1.google.com
+const DEFAULT_DETECT_SERVER: &str = "216.239.32.10:53";
+const DETECT_INTERVAL: Duration = Duration::from_secs(1800);
+const DETECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// decides which client subnet (RFC 7871) is revealed to upstreams
+pub struct ClientSubnetPolicy {
+    policy: EcsPolicy,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    subnets: HashMap<String, (IpAddr, u8)>,
+    detect_server: String,
+    detected: RwLock<Option<IpAddr>>,
+}
+
+impl ClientSubnetPolicy {
+    pub fn new(settings: &DNSSettings) -> Self {
+        let ecs = match &settings.ecs {
+            Some(ecs) => ecs,
+            None => {
+                return ClientSubnetPolicy {
+                    policy: EcsPolicy::Strip,
+                    ipv4_prefix: 0,
+                    ipv6_prefix: 0,
+                    subnets: HashMap::new(),
+                    detect_server: DEFAULT_DETECT_SERVER.to_string(),
+                    detected: RwLock::new(None),
+                }
+            }
+        };
+
+        let mut subnets = HashMap::new();
+        if let Some(configured) = &ecs.subnets {
+            for (group, subnet) in configured {
+                match parse_subnet(subnet) {
+                    Some(subnet) => {
+                        subnets.insert(group.clone(), subnet);
+                    }
+                    None => panic!("invalid ecs subnet {} for group {}", subnet, group),
+                }
+            }
+        }
+
+        ClientSubnetPolicy {
+            policy: ecs.policy,
+            ipv4_prefix: ecs.ipv4_prefix.unwrap_or(24).min(32),
+            ipv6_prefix: ecs.ipv6_prefix.unwrap_or(56).min(128),
+            subnets,
+            detect_server: ecs
+                .detect_server
+                .clone()
+                .unwrap_or_else(|| DEFAULT_DETECT_SERVER.to_string()),
+            detected: RwLock::new(None),
+        }
+    }
+
+    pub fn is_auto(&self) -> bool {
+        self.policy == EcsPolicy::Auto
+    }
+
+    // the subnet to send along with a query to upstreams of the given group, if any
+    pub fn get_client_subnet(
+        &self,
+        origin: &Message<Vec<u8>>,
+        client_ip: Option<IpAddr>,
+        group: &str,
+    ) -> Option<(IpAddr, u8)> {
+        let (ip, prefix) = match self.policy {
+            EcsPolicy::Strip => return None,
+            // configured subnets are sent as they are
+            EcsPolicy::Fixed => return self.subnets.get(group).copied(),
+            // a subnet sent by the client is honored, including a 0 prefix that opts out
+            EcsPolicy::Forward => get_origin_subnet(origin)
+                .or_else(|| client_ip.filter(is_public_ip).map(|ip| (ip, 128)))?,
+            EcsPolicy::Auto => ((*self.detected.read().unwrap())?, 128),
+        };
+
+        let prefix = match ip {
+            IpAddr::V4(_) => prefix.min(self.ipv4_prefix),
+            IpAddr::V6(_) => prefix.min(self.ipv6_prefix),
+        };
+        Some((mask_ip(ip, prefix), prefix))
+    }
+
+    // the subnet answers to a client may be tailored to, none if it is the same for every client,
+    // `fixed` subnets only depend on the group, which is part of the route of a domain
+    pub fn get_cache_scope(
+        &self,
+        origin: &Message<Vec<u8>>,
+        client_ip: Option<IpAddr>,
+    ) -> Option<String> {
+        match self.policy {
+            EcsPolicy::Forward | EcsPolicy::Auto => {
+                let (ip, prefix) = self.get_client_subnet(origin, client_ip, "")?;
+                Some(format!("{}/{}", ip, prefix))
+            }
+            EcsPolicy::Strip | EcsPolicy::Fixed => None,
+        }
+    }
+
+    // keep our public address up to date for the `auto` policy
+    pub async fn keep_detected(self: Arc<Self>) {
+        loop {
+            match detect_public_ip(&self.detect_server).await {
+                Ok(ip) => {
+                    let mut detected = self.detected.write().unwrap();
+                    if *detected != Some(ip) {
+                        println!("[ECS] Detected public address {}.", ip);
+                        *detected = Some(ip);
+                    }
+                }
+                Err(e) => {
+                    println!("[ECS] Failed to detect public address: {}", e);
+                }
+            }
+            sleep(DETECT_INTERVAL).await;
+        }
+    }
+}
+
+async fn detect_public_ip(server: &str) -> Result<IpAddr, Error> {
+    let server: SocketAddr = server.parse().map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid detect server {}", server),
+        )
+    })?;
+    let local = if server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+
+    let id = rand::random::<u16>();
+    let mut msg = MessageBuilder::new_vec();
+    msg.header_mut().set_id(id);
+    let mut msg = msg.question();
+    msg.push((Dname::vec_from_str(MYADDR_NAME).unwrap(), Rtype::Txt))
+        .unwrap();
+    let request = msg.finish();
+
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+    socket.send(&request).await?;
+
+    let mut buf = vec![0u8; 4096];
+    let size = timeout(DETECT_TIMEOUT, socket.recv(&mut buf)).await??;
+    let response = Message::from_octets(buf[..size].to_vec())
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid response"))?;
+    if response.header().id() != id {
+        return Err(Error::new(ErrorKind::InvalidData, "mismatched response id"));
+    }
+
+    let answers = response
+        .answer()
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid response"))?
+        .limit_to::<AllRecordData<_, _>>();
+    for answer in answers.flatten() {
+        if let AllRecordData::Txt(txt) = answer.data() {
+            if let Ok(ip) = txt.to_string().trim_matches('"').parse::<IpAddr>() {
+                return Ok(ip);
+            }
+        }
+    }
+
+    Err(Error::new(ErrorKind::NotFound, "no address in response"))
+}
+
+// the first client subnet option of a query
+fn get_origin_subnet(origin: &Message<Vec<u8>>) -> Option<(IpAddr, u8)> {
+    let opt = origin.opt()?;
+    let subnet = opt.as_opt().iter::<ClientSubnet>().flatten().next()?;
+    Some((subnet.addr(), subnet.source_prefix_len()))
+}
+
+// `1.2.3.0/24`, or a single address
+fn parse_subnet(subnet: &str) -> Option<(IpAddr, u8)> {
+    let mut parts = subnet.splitn(2, '/');
+    let ip = parts.next()?.trim().parse::<IpAddr>().ok()?;
+    let max_prefix = if ip.is_ipv4() { 32 } else { 128 };
+    let prefix = match parts.next() {
+        Some(prefix) => prefix.trim().parse::<u8>().ok()?,
+        None => max_prefix,
+    };
+    if prefix > max_prefix {
+        return None;
+    }
+    Some((mask_ip(ip, prefix), prefix))
+}
+
+// clear the host bits, which must be zero in the option (RFC 7871 6)
+fn mask_ip(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+    }
+}
+
+// addresses of private networks mean nothing to upstreams
+fn is_public_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            // 100.64.0.0/10 is shared by carrier-grade nat
+            let is_shared = octets[0] == 100 && octets[1] & 0xc0 == 64;
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || is_shared)
+        }
+        IpAddr::V6(ip) => {
+            let head = ip.segments()[0];
+            // fc00::/7 unique local and fe80::/10 link local
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || head & 0xfe00 == 0xfc00
+                || head & 0xffc0 == 0xfe80)
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use domain::base::{
+    iana::OptionCode,
+    octets::{Compose, OctetsBuilder, ShortBuf},
+    opt::OptData,
+};
+
+// info codes of Extended DNS Errors, see RFC 8914 section 4
+#[derive(Clone, Copy, Debug)]
+pub enum ExtendedErrorCode {
+    Other = 0,
+    StaleAnswer = 3,
+    NotSupported = 21,
+    NoReachableAuthority = 22,
+    NetworkError = 23,
+    InvalidData = 24,
+}
+
+// Extended DNS Error option (RFC 8914), which is not shipped with domain 0.6
+#[derive(Clone, Debug)]
+pub struct ExtendedError {
+    code: ExtendedErrorCode,
+    text: String,
+}
+
+impl ExtendedError {
+    pub fn new(code: ExtendedErrorCode, text: &str) -> Self {
+        ExtendedError {
+            code,
+            text: text.to_string(),
+        }
+    }
+}
+
+impl Compose for ExtendedError {
+    fn compose<T: OctetsBuilder>(&self, target: &mut T) -> Result<(), ShortBuf> {
+        target.append_slice(&(self.code as u16).to_be_bytes())?;
+        target.append_slice(self.text.as_bytes())
+    }
+}
+
+impl OptData for ExtendedError {
+    fn code(&self) -> OptionCode {
+        OptionCode::from_int(15)
+    }
+}
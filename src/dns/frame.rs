@@ -0,0 +1,99 @@
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// the largest message a 2-byte length prefix can describe
+pub const MAX_FRAME_SIZE: usize = 65535;
+
+#[derive(Debug)]
+pub enum FrameError {
+    // the peer closed the connection between two messages
+    Closed,
+    // the declared length is smaller than a dns header
+    TooShort(usize),
+    // the declared length exceeds what the reader accepts
+    Oversize(usize),
+    Io(Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Closed => write!(f, "connection closed"),
+            FrameError::TooShort(size) => write!(f, "message of {} bytes is too short", size),
+            FrameError::Oversize(size) => write!(f, "message of {} bytes is too large", size),
+            FrameError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<Error> for FrameError {
+    fn from(e: Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+impl From<FrameError> for Error {
+    fn from(e: FrameError) -> Self {
+        match e {
+            FrameError::Closed => Error::new(ErrorKind::UnexpectedEof, e),
+            FrameError::TooShort(_) | FrameError::Oversize(_) => {
+                Error::new(ErrorKind::InvalidData, e)
+            }
+            FrameError::Io(e) => e,
+        }
+    }
+}
+
+// Reads 2-byte length prefixed messages (RFC 1035 4.2.2) from tcp based transports.
+// A message is always read in full, however many segments it is split into.
+pub struct FramedReader<R> {
+    reader: R,
+    max_size: usize,
+}
+
+impl<R: AsyncRead + Unpin> FramedReader<R> {
+    pub fn new(reader: R, max_size: usize) -> Self {
+        FramedReader { reader, max_size }
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub async fn read_frame(&mut self) -> Result<Vec<u8>, FrameError> {
+        let mut head = [0u8; 2];
+        // eof before the first byte is a clean close, anywhere later it's a truncated message
+        if self.reader.read(&mut head[..1]).await? == 0 {
+            return Err(FrameError::Closed);
+        }
+        self.reader.read_exact(&mut head[1..]).await?;
+
+        let size = u16::from_be_bytes(head) as usize;
+        if size > self.max_size {
+            return Err(FrameError::Oversize(size));
+        }
+
+        let mut buf = vec![0u8; size];
+        self.reader.read_exact(&mut buf).await?;
+
+        // checked after reading, so the stream stays in sync
+        if size < 12 {
+            return Err(FrameError::TooShort(size));
+        }
+
+        Ok(buf)
+    }
+}
+
+// add 2-byte length head to a message buffer, for tcp based transports
+pub fn get_wrapped_buf(buf: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(2 + buf.len());
+    packet.extend_from_slice(&(buf.len() as u16).to_be_bytes());
+    packet.extend_from_slice(buf);
+    packet
+}
@@ -0,0 +1,88 @@
+use domain::base::Message;
+use hyper::{
+    body::to_bytes,
+    header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE},
+    Body, Method, Request, Response, StatusCode,
+};
+
+const DNS_MESSAGE: &str = "application/dns-message";
+const MAX_MESSAGE_SIZE: u64 = 65535;
+
+// extract the dns query of a RFC 8484 request, or the status code to reject it with
+pub async fn get_doh_query(req: Request<Body>, path: &str) -> Result<Vec<u8>, StatusCode> {
+    if req.uri().path() != path {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let buf = match *req.method() {
+        Method::GET => {
+            let query = req.uri().query().unwrap_or_default();
+            let param = query
+                .split('&')
+                .find(|pair| pair.starts_with("dns="))
+                .map(|pair| pair[4..].trim_end_matches('='))
+                .ok_or(StatusCode::BAD_REQUEST)?;
+            base64::decode_config(param, base64::URL_SAFE_NO_PAD)
+                .map_err(|_| StatusCode::BAD_REQUEST)?
+        }
+        Method::POST => {
+            let content_type = req
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default();
+            if !content_type.eq_ignore_ascii_case(DNS_MESSAGE) {
+                return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            if let Some(size) = hyper::body::HttpBody::size_hint(req.body()).upper() {
+                if size > MAX_MESSAGE_SIZE {
+                    return Err(StatusCode::PAYLOAD_TOO_LARGE);
+                }
+            }
+            let body = to_bytes(req.into_body())
+                .await
+                .map_err(|_| StatusCode::BAD_REQUEST)?;
+            if body.len() as u64 > MAX_MESSAGE_SIZE {
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            body.to_vec()
+        }
+        _ => return Err(StatusCode::METHOD_NOT_ALLOWED),
+    };
+
+    // at least a full dns header
+    if buf.len() < 12 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    Ok(buf)
+}
+
+pub fn get_doh_response(buf: Vec<u8>) -> Response<Body> {
+    let max_age = get_min_ttl(&buf);
+    let mut response = Response::new(Body::from(buf));
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(DNS_MESSAGE));
+    if let Some(max_age) = max_age {
+        if let Ok(value) = HeaderValue::from_str(format!("max-age={}", max_age).as_str()) {
+            headers.insert(CACHE_CONTROL, value);
+        }
+    }
+    response
+}
+
+pub fn get_doh_error_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+// the freshness lifetime of a response is the smallest ttl of its answers (RFC 8484 5.1)
+fn get_min_ttl(buf: &[u8]) -> Option<u32> {
+    let message = Message::from_octets(buf.to_vec()).ok()?;
+    let answers = message.answer().ok()?;
+    answers
+        .filter_map(|record| record.ok())
+        .map(|record| record.ttl())
+        .min()
+}
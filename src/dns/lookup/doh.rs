@@ -1,85 +1,92 @@
+use super::pool::ConnectionPool;
 use super::utils::{is_valid_response, QueryResponse};
+use crate::dns::settings::{DNSServerUpstream, UpstreamEndpoint};
 use domain::base::Message;
-use rustls_native_certs::load_native_certs;
-use std::io::{Error, ErrorKind};
-use std::str::FromStr;
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::{net::TcpStream, time::timeout, time::Duration};
-use tokio_rustls::{
-    rustls::{ClientConfig, ProtocolVersion},
-    webpki::DNSNameRef,
-    TlsConnector,
+use hyper::{
+    body::to_bytes,
+    header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE},
+    Body, Request, StatusCode,
 };
+use std::io::{Error, ErrorKind};
+use tokio::time::timeout;
+
+const DNS_MESSAGE: &str = "application/dns-message";
 
 pub async fn lookup_doh(
     message: &Message<Vec<u8>>,
-    remote_addr: &std::string::String,
-    hostname: &std::string::String,
+    upstream: &DNSServerUpstream,
+    endpoint: &UpstreamEndpoint,
+    pool: &ConnectionPool,
 ) -> Result<QueryResponse, Error> {
-    let mut config = ClientConfig::new();
-    config.root_store = load_native_certs().unwrap();
-    config.enable_sni = true;
-    config.enable_early_data = true;
-    config.versions = vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2];
-    let connector = TlsConnector::from(Arc::new(config));
-    let socket = TcpStream::connect(format!("{}:{}", remote_addr, 443)).await?;
-
-    let mut socket = connector
-        .connect(DNSNameRef::try_from_ascii_str(hostname).unwrap(), socket)
-        .await?;
-
-    // let packet = self.get_wrapped_packet(message);
-    let packet = message.as_octets();
-
-    let mut data = std::string::String::new();
-    data.push_str("POST /dns-query HTTP/1.1\r\n");
-    data.push_str(format!("Host: {}\r\n", hostname).as_str());
-    data.push_str("Content-Type: application/dns-message\r\n");
-    data.push_str(format!("Content-Length: {}\r\n", packet.len()).as_str());
-    data.push_str("\r\n");
-
-    socket.write(&data.as_bytes()).await?;
-    socket.write(&packet).await?;
+    let client = pool.get_https_client(upstream, endpoint);
+    let is_get = upstream.is_doh_get();
+    let request = get_doh_request(message, endpoint, is_get)?;
 
-    // It stores the response message
-    let mut packet = Vec::with_capacity(1024);
+    let exchange = async {
+        let response = client.request(request).await.map_err(|e| {
+            Error::new(
+                ErrorKind::ConnectionAborted,
+                format!("[DoH] Request to {} failed: {}", endpoint, e),
+            )
+        })?;
 
-    timeout(Duration::from_millis(2000), socket.read_buf(&mut packet)).await??;
-
-    if std::str::from_utf8(&packet[..15]).unwrap() != "HTTP/1.1 200 OK" {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "[DoH] non-200 status response.".to_string(),
-        ));
-    }
-
-    // find response body size
-    let size_position = match packet
-        .windows(15)
-        .position(|s| std::str::from_utf8(&s).unwrap() == "Content-Length:")
-    {
-        Some(p) => p,
-        None => {
+        let status = response.status();
+        if status != StatusCode::OK {
             return Err(Error::new(
                 ErrorKind::Other,
-                "[DoH] Wrong response.".to_string(),
+                format!("[DoH] {} responded with status {}.", endpoint, status),
+            ));
+        }
+
+        // parameters like `; charset=...` are not expected, but tolerated
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .unwrap_or_default()
+            .trim()
+            .to_string();
+        if !content_type.eq_ignore_ascii_case(DNS_MESSAGE) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "[DoH] {} responded with unexpected content type \"{}\".",
+                    endpoint, content_type
+                ),
             ));
         }
+
+        to_bytes(response.into_body()).await.map_err(|e| {
+            Error::new(
+                ErrorKind::ConnectionAborted,
+                format!(
+                    "[DoH] Failed to read response body from {}: {}",
+                    endpoint, e
+                ),
+            )
+        })
     };
-    let body_size: Vec<u8> = packet
-        .iter()
-        .skip(size_position + 15)
-        .take_while(|s| **s != 13)
-        .map(|c| *c)
-        .collect();
-    let body_size = std::str::from_utf8(&body_size).unwrap().trim();
-    let body_size = usize::from_str(body_size).unwrap();
+    let body = timeout(upstream.read_timeout(), exchange).await??;
 
-    // slice DNS message out of http response message
-    let packet: Vec<u8> = packet.into_iter().rev().take(body_size).rev().collect();
+    if body.len() < 12 || body.len() > 65535 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "[DoH] {} responded with a body of invalid size {}.",
+                endpoint,
+                body.len()
+            ),
+        ));
+    }
 
-    let ret_message = Message::from_octets(packet.to_vec()).unwrap();
+    let mut packet = body.to_vec();
+    if is_get {
+        // restore the id cleared for http caching
+        packet[..2].copy_from_slice(&message.header().id().to_be_bytes());
+    }
+    let ret_message = Message::from_octets(packet)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "[DoH] Invalid response."))?;
 
     if is_valid_response(&ret_message) {
         return Ok(QueryResponse::DoH(ret_message));
@@ -87,6 +94,51 @@ pub async fn lookup_doh(
 
     Err(Error::new(
         ErrorKind::InvalidData,
-        "[DoH] Packet size checking failed.".to_string(),
+        "[DoH] Invalid response.".to_string(),
     ))
 }
+
+// build a RFC 8484 request, the uri is in absolute form which works for both HTTP/2 and HTTP/1.1
+fn get_doh_request(
+    message: &Message<Vec<u8>>,
+    endpoint: &UpstreamEndpoint,
+    is_get: bool,
+) -> Result<Request<Body>, Error> {
+    let authority = if endpoint.port == 443 {
+        endpoint.hostname.clone()
+    } else {
+        format!("{}:{}", endpoint.hostname, endpoint.port)
+    };
+    let uri = format!("https://{}{}", authority, endpoint.path);
+    let packet = message.as_octets();
+
+    let request = if is_get {
+        // id should be 0 in GET requests to make responses cache friendly (RFC 8484 4.1)
+        let mut packet = packet.clone();
+        packet[0] = 0;
+        packet[1] = 0;
+        let separator = if uri.contains('?') { '&' } else { '?' };
+        let uri = format!(
+            "{}{}dns={}",
+            uri,
+            separator,
+            base64::encode_config(&packet, base64::URL_SAFE_NO_PAD)
+        );
+        Request::get(uri)
+            .header(ACCEPT, DNS_MESSAGE)
+            .body(Body::empty())
+    } else {
+        Request::post(uri)
+            .header(ACCEPT, DNS_MESSAGE)
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .header(CONTENT_LENGTH, packet.len())
+            .body(Body::from(packet.clone()))
+    };
+
+    request.map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("[DoH] Invalid request to {}: {}", endpoint, e),
+        )
+    })
+}
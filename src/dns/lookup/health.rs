@@ -0,0 +1,249 @@
+use super::{lookup, pool::ConnectionPool};
+use crate::dns::settings::{DNSServerUpstream, UpstreamEndpoint};
+use domain::base::{iana::Rtype, Dname, Message, MessageBuilder};
+use std::{collections::HashMap, fmt, sync::Arc, sync::Mutex};
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+// weight of the newest sample in moving averages
+const EWMA_WEIGHT: f64 = 0.2;
+// consecutive failures before an endpoint is ejected
+const EJECT_FAILURES: u32 = 5;
+// the first ejection lasts this long, and it doubles on every further ejection
+const EJECT_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_EJECT_BACKOFF: Duration = Duration::from_secs(300);
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+const REPORT_INTERVAL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone)]
+pub struct EndpointHealth {
+    // moving average of response times in milliseconds, none before the first success
+    pub latency: Option<f64>,
+    // moving average of successes, from 0 to 1
+    pub success_rate: f64,
+    pub consecutive_failures: u32,
+    // ejections since the endpoint last answered a query
+    pub ejections: u32,
+    // ejected endpoints take no queries until a probe succeeds after this
+    pub ejected_until: Option<Instant>,
+    probing: bool,
+}
+
+impl Default for EndpointHealth {
+    fn default() -> Self {
+        EndpointHealth {
+            latency: None,
+            success_rate: 1.0,
+            consecutive_failures: 0,
+            ejections: 0,
+            ejected_until: None,
+            probing: false,
+        }
+    }
+}
+
+impl EndpointHealth {
+    // lower is better, failures make an endpoint look slower, and endpoints which never
+    // answered come after all the others, as they may well be unreachable
+    pub fn score(&self) -> f64 {
+        match self.latency {
+            Some(latency) => latency / self.success_rate.max(0.01),
+            None => f64::INFINITY,
+        }
+    }
+}
+
+impl fmt::Display for EndpointHealth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.latency {
+            Some(latency) => write!(f, "latency {:.0}ms, ", latency)?,
+            None => write!(f, "latency -, ")?,
+        }
+        write!(f, "success {:.0}%, ", self.success_rate * 100.0)?;
+        if self.ejected_until.is_some() {
+            write!(f, "ejected {} times", self.ejections)
+        } else {
+            write!(f, "available")
+        }
+    }
+}
+
+// Health of every endpoint, that is an upstream with one of its protocols,
+// keyed by the endpoint url.
+pub struct HealthRegistry {
+    states: Mutex<HashMap<String, EndpointHealth>>,
+    // turns of round robin groups
+    rounds: Mutex<HashMap<String, usize>>,
+}
+
+impl HealthRegistry {
+    pub fn new() -> Self {
+        HealthRegistry {
+            states: Mutex::new(HashMap::new()),
+            rounds: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn next_round(&self, group: &str) -> usize {
+        let mut rounds = self.rounds.lock().unwrap();
+        let round = rounds.entry(group.to_string()).or_insert(0);
+        let current = *round;
+        *round = round.wrapping_add(1);
+        current
+    }
+
+    pub fn get_health(&self, endpoint: &UpstreamEndpoint) -> EndpointHealth {
+        self.states
+            .lock()
+            .unwrap()
+            .get(&endpoint.to_string())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn is_available(&self, endpoint: &UpstreamEndpoint) -> bool {
+        match self.states.lock().unwrap().get(&endpoint.to_string()) {
+            Some(state) => state.ejected_until.is_none(),
+            None => true,
+        }
+    }
+
+    pub fn record_success(&self, endpoint: &UpstreamEndpoint, elapsed: Duration) {
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(endpoint.to_string()).or_default();
+        let elapsed = elapsed.as_secs_f64() * 1000.0;
+        state.latency = Some(match state.latency {
+            Some(latency) => latency * (1.0 - EWMA_WEIGHT) + elapsed * EWMA_WEIGHT,
+            None => elapsed,
+        });
+        state.success_rate = state.success_rate * (1.0 - EWMA_WEIGHT) + EWMA_WEIGHT;
+        state.consecutive_failures = 0;
+        state.ejections = 0;
+        // answering a query is as good as a probe
+        state.ejected_until = None;
+    }
+
+    pub fn record_failure(&self, endpoint: &UpstreamEndpoint) {
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(endpoint.to_string()).or_default();
+        state.success_rate *= 1.0 - EWMA_WEIGHT;
+        state.consecutive_failures += 1;
+        if state.ejected_until.is_none() && state.consecutive_failures >= EJECT_FAILURES {
+            eject(endpoint, state);
+        }
+    }
+
+    // probe ejected endpoints once their back-off is over, and report the state periodically
+    pub async fn keep_probing(
+        self: Arc<Self>,
+        upstreams: Vec<DNSServerUpstream>,
+        pool: Arc<ConnectionPool>,
+    ) {
+        let upstreams = Arc::new(upstreams);
+        let mut next_report = Instant::now() + REPORT_INTERVAL;
+        loop {
+            sleep(PROBE_INTERVAL).await;
+
+            for (i, upstream) in upstreams.iter().enumerate() {
+                for (j, endpoint) in upstream.endpoints.iter().enumerate() {
+                    if !self.start_probe(endpoint) {
+                        continue;
+                    }
+                    let registry = self.clone();
+                    let upstreams = upstreams.clone();
+                    let pool = pool.clone();
+                    tokio::spawn(async move {
+                        let upstream = &upstreams[i];
+                        registry
+                            .probe(upstream, &upstream.endpoints[j], &pool)
+                            .await;
+                    });
+                }
+            }
+
+            if Instant::now() >= next_report {
+                next_report = Instant::now() + REPORT_INTERVAL;
+                self.report(&upstreams);
+            }
+        }
+    }
+
+    pub fn report(&self, upstreams: &[DNSServerUpstream]) {
+        for upstream in upstreams {
+            for endpoint in &upstream.endpoints {
+                println!("[Health] {}: {}", endpoint, self.get_health(endpoint));
+            }
+        }
+    }
+
+    // whether a probe should be sent to the endpoint now, marks it as being probed if so
+    fn start_probe(&self, endpoint: &UpstreamEndpoint) -> bool {
+        let mut states = self.states.lock().unwrap();
+        match states.get_mut(&endpoint.to_string()) {
+            Some(state) => match state.ejected_until {
+                Some(until) if until <= Instant::now() && !state.probing => {
+                    state.probing = true;
+                    true
+                }
+                _ => false,
+            },
+            None => false,
+        }
+    }
+
+    async fn probe(
+        &self,
+        upstream: &DNSServerUpstream,
+        endpoint: &UpstreamEndpoint,
+        pool: &ConnectionPool,
+    ) {
+        let message = get_probe_message();
+        let probe_timeout = upstream.connect_timeout() + upstream.read_timeout();
+        let result = timeout(probe_timeout, lookup(&message, upstream, endpoint, pool)).await;
+
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(endpoint.to_string()).or_default();
+        state.probing = false;
+        match result {
+            Ok(Ok(_)) => {
+                println!("[Health] {} is back after a successful probe.", endpoint);
+                state.ejected_until = None;
+                state.consecutive_failures = 0;
+            }
+            // unless a query got answered meanwhile
+            _ if state.ejected_until.is_some() => eject(endpoint, state),
+            _ => {}
+        }
+    }
+}
+
+fn eject(endpoint: &UpstreamEndpoint, state: &mut EndpointHealth) {
+    let backoff = EJECT_BACKOFF
+        .checked_mul(1 << state.ejections.min(16))
+        .unwrap_or(MAX_EJECT_BACKOFF)
+        .min(MAX_EJECT_BACKOFF);
+    state.ejections += 1;
+    state.ejected_until = Some(Instant::now() + backoff);
+    println!(
+        "[Health] {} is ejected for {}s, {}.",
+        endpoint,
+        backoff.as_secs(),
+        state
+    );
+}
+
+// the root zone SOA, which every resolver is able to answer
+fn get_probe_message() -> Message<Vec<u8>> {
+    let mut msg = MessageBuilder::new_vec();
+    msg.header_mut().set_id(rand::random());
+    msg.header_mut().set_rd(true);
+    let mut msg = msg.question();
+    msg.push((Dname::root_vec(), Rtype::Soa)).unwrap();
+    let mut msg = msg.additional();
+    msg.opt(|opt| {
+        opt.set_udp_payload_size(1232);
+        opt.set_dnssec_ok(true);
+        Ok(())
+    })
+    .unwrap();
+    Message::from_octets(msg.finish()).unwrap()
+}
@@ -1,78 +1,229 @@
 mod doh;
 mod dot;
+pub mod health;
+pub mod pool;
+mod strategy;
 mod tcp;
 mod udp;
 pub mod utils;
 
-use super::settings::DNSServerUpstream;
+use super::settings::{DNSServerUpstream, DNSSettings, GroupAccept, UpstreamEndpoint};
+use super::utils::get_companion_message;
 use crate::router::GeoIP;
 use doh::*;
-use domain::base::Message;
+use domain::base::{iana::Rtype, Message};
 use dot::*;
-use futures::future::select_ok;
-use std::{io::Error, sync::Arc, time::Duration};
+use health::HealthRegistry;
+use pool::ConnectionPool;
+use std::{
+    io::{Error, ErrorKind},
+    sync::Arc,
+};
+use strategy::query_group;
 use tcp::*;
-use tokio::time::timeout;
+use tokio::time::{timeout, timeout_at, Duration, Instant};
 use udp::*;
-use utils::{get_message_from_response_ref, is_china_site, QueryResponse, QueryType};
+use utils::{
+    get_message_from_response_ref, has_bogus_ip, is_home_site, is_matching_response, QueryResponse,
+    QueryType,
+};
+
+// how long an AAAA answer waits for its companion A answer, once it is back
+const COMPANION_WAIT: Duration = Duration::from_millis(200);
 
 pub async fn lookup(
-    t: QueryType,
     message: &Message<Vec<u8>>,
     upstream: &DNSServerUpstream,
+    endpoint: &UpstreamEndpoint,
+    pool: &ConnectionPool,
 ) -> Result<QueryResponse, Error> {
-    match t {
-        QueryType::UDP => lookup_udp(message, &upstream.address).await,
-        QueryType::TCP => lookup_tcp(message, &upstream.address).await,
-        QueryType::DoT => lookup_dot(message, &upstream.address, &upstream.hostname).await,
-        QueryType::DoH => lookup_doh(message, &upstream.address, &upstream.hostname).await,
+    let response = match endpoint.protocol {
+        QueryType::UDP => lookup_udp(message, upstream, endpoint, pool).await?,
+        QueryType::TCP => lookup_tcp(message, upstream, endpoint, pool).await?,
+        QueryType::DoT => lookup_dot(message, upstream, endpoint, pool).await?,
+        QueryType::DoH => lookup_doh(message, upstream, endpoint, pool).await?,
         QueryType::Custom => panic!("Custom query should be performed independently"),
         QueryType::Cache => panic!("Cache query should be performed independently"),
+        QueryType::Error => panic!("Error response should be built independently"),
+    };
+
+    let (ret_message, _) = get_message_from_response_ref(&response);
+    if !is_matching_response(message, ret_message, upstream.case_randomization()) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Response from {} does not match the query.", endpoint),
+        ));
     }
+    Ok(response)
 }
 
-pub async fn batch_query(
+// Ask the groups routed for the domain one after another, until a group gives an answer it accepts.
+// `get_request` builds the query to each endpoint from the client's one,
+// as options differ between groups and transports.
+pub async fn batch_query<F>(
     message: &Message<Vec<u8>>,
-    upstreams: &Vec<DNSServerUpstream>,
+    get_request: F,
+    domain: &str,
+    settings: &DNSSettings,
     geoip: Arc<GeoIP>,
-) -> Result<(QueryResponse, bool), Error> {
-    let mut queries_china = vec![];
-    let mut queries_abroad = vec![];
-
-    for upstream in upstreams {
-        let queries;
-        if upstream.is_china {
-            queries = &mut queries_china;
-        } else {
-            queries = &mut queries_abroad;
+    pool: Arc<ConnectionPool>,
+    health: Arc<HealthRegistry>,
+) -> Result<(QueryResponse, String), Error>
+where
+    F: Fn(&Message<Vec<u8>>, &DNSServerUpstream, &UpstreamEndpoint) -> Message<Vec<u8>>,
+{
+    // the same name asking for A records, to classify AAAA queries the way ipv4 ones are
+    let companion_message = match message.first_question() {
+        Some(question) if question.qtype() == Rtype::Aaaa => {
+            Some(get_companion_message(message, Rtype::A))
         }
+        _ => None,
+    };
 
-        if upstream.enable_udp {
-            let ret_message = lookup(QueryType::UDP, message, &upstream);
-            queries.push(Box::pin(ret_message));
+    // all groups share the same budget
+    let deadline = Instant::now() + settings.query_timeout();
+    let route = settings.get_route(domain);
+
+    let mut last_error = Error::new(ErrorKind::NotFound, "no upstream group to query");
+    // an answer refused by its group, still better than nothing if the rest fail
+    let mut refused = None;
+    for (i, name) in route.iter().enumerate() {
+        let group = settings.get_group(name);
+        let mut endpoints = vec![];
+        for upstream in &settings.upstreams {
+            if upstream.group() != name {
+                continue;
+            }
+            for endpoint in &upstream.endpoints {
+                endpoints.push((upstream, endpoint));
+            }
         }
-        if upstream.enable_tcp {
-            let ret_message = lookup(QueryType::TCP, message, &upstream);
-            queries.push(Box::pin(ret_message));
+
+        let group_deadline = match group.query_timeout() {
+            Some(query_timeout) => deadline.min(Instant::now() + query_timeout),
+            None => deadline,
+        };
+        let build = |upstream: &DNSServerUpstream, endpoint: &UpstreamEndpoint| {
+            get_request(message, upstream, endpoint)
+        };
+        let query = query_group(name, &group, &build, endpoints.clone(), &pool, &health);
+
+        let companion_query = async {
+            let companion_message = companion_message
+                .as_ref()
+                .filter(|_| group.accept() == GroupAccept::Geoip && group.companion_a())?;
+            let build = |upstream: &DNSServerUpstream, endpoint: &UpstreamEndpoint| {
+                get_request(companion_message, upstream, endpoint)
+            };
+            query_group(name, &group, &build, endpoints, &pool, &health)
+                .await
+                .ok()
+        };
+
+        let query = timeout_at(group_deadline, query);
+        let companion_query = timeout_at(group_deadline, companion_query);
+        tokio::pin!(query, companion_query);
+        let (response, companion_response) = tokio::select! {
+            response = &mut query => {
+                // the answer is not held up long for its companion, without which
+                // it is classified by itself
+                let companion_response = match response {
+                    Ok(Ok(_)) => timeout(COMPANION_WAIT, &mut companion_query)
+                        .await
+                        .ok()
+                        .and_then(|response| response.ok())
+                        .flatten(),
+                    _ => None,
+                };
+                (response, companion_response)
+            }
+            companion_response = &mut companion_query => {
+                (query.await, companion_response.ok().flatten())
+            }
+        };
+        let response = match response {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => {
+                last_error = e;
+                continue;
+            }
+            Err(e) => {
+                last_error = e.into();
+                continue;
+            }
+        };
+
+        let (ret_message, _) = get_message_from_response_ref(&response);
+        if has_bogus_ip(ret_message, &settings.bogus_set) {
+            println!(
+                "[{}] Answer of {} contains a bogus address, ignored.",
+                name, domain
+            );
+            last_error = Error::new(ErrorKind::InvalidData, "forged answer");
+            continue;
         }
-        if upstream.enable_dot {
-            let ret_message = lookup(QueryType::DoT, message, &upstream);
-            queries.push(Box::pin(ret_message));
+
+        let is_last = i + 1 == route.len();
+        let accepted = match group.accept() {
+            GroupAccept::Any => true,
+            GroupAccept::Geoip => {
+                let (ret_message, _) = match &companion_response {
+                    Some(companion_response) => get_message_from_response_ref(companion_response),
+                    None => get_message_from_response_ref(&response),
+                };
+                is_home_site(ret_message, geoip.clone())
+            }
+        };
+        if is_last || accepted {
+            return Ok((response, name.clone()));
         }
-        if upstream.enable_doh {
-            let ret_message = lookup(QueryType::DoH, message, &upstream);
-            queries.push(Box::pin(ret_message));
+        if refused.is_none() {
+            refused = Some((response, name.clone()));
         }
     }
 
-    let duration = Duration::from_millis(5000);
-
-    let (response, _) = timeout(duration, select_ok(queries_china)).await??;
-    let (ret_message, _) = get_message_from_response_ref(&response);
-    if is_china_site(&ret_message, geoip) {
-        return Ok((response, true));
+    match refused {
+        Some(refused) => Ok(refused),
+        None => Err(last_error),
     }
+}
 
-    let (response, _) = timeout(duration, select_ok(queries_abroad)).await??;
-    Ok((response, false))
+// Lookup and record the outcome to the health registry.
+// The lookup runs in a task of its own, so that it is recorded even when the caller stops
+// waiting for it, e.g. when another endpoint of a race group answered first,
+// otherwise an endpoint which never answers would never be taken as failing.
+async fn lookup_with_health(
+    message: Message<Vec<u8>>,
+    upstream: &DNSServerUpstream,
+    endpoint: &UpstreamEndpoint,
+    pool: &Arc<ConnectionPool>,
+    health: &Arc<HealthRegistry>,
+) -> Result<QueryResponse, Error> {
+    let upstream = upstream.clone();
+    let endpoint = endpoint.clone();
+    let pool = pool.clone();
+    let health = health.clone();
+    let task = tokio::spawn(async move {
+        let start = Instant::now();
+        let lookup_timeout = upstream.connect_timeout() + upstream.read_timeout();
+        let result = match timeout(
+            lookup_timeout,
+            lookup(&message, &upstream, &endpoint, &pool),
+        )
+        .await
+        {
+            Ok(result) => result,
+            Err(_) => Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("{} timed out.", endpoint),
+            )),
+        };
+        match &result {
+            Ok(_) => health.record_success(&endpoint, start.elapsed()),
+            Err(_) => health.record_failure(&endpoint),
+        }
+        result
+    });
+    task.await
+        .unwrap_or_else(|e| Err(Error::new(ErrorKind::Other, e)))
 }
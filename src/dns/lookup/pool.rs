@@ -0,0 +1,431 @@
+use crate::dns::{
+    frame::{get_wrapped_buf, FrameError, FramedReader, MAX_FRAME_SIZE},
+    settings::{DNSServerUpstream, UpstreamEndpoint},
+};
+use domain::base::{opt::TcpKeepalive, Message};
+use futures::Future;
+use hyper::{
+    client::connect::{Connected, Connection},
+    service::Service,
+    Client, Uri,
+};
+use rustls_native_certs::load_native_certs;
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU16, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{split, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf},
+    net::TcpStream,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedSender},
+        oneshot, Mutex,
+    },
+    time::{timeout, Duration},
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{ClientConfig, ProtocolVersion, RootCertStore, Session},
+    webpki::DNSNameRef,
+    TlsConnector,
+};
+
+use super::utils::QueryType;
+
+// the longest time a started message may take to arrive completely
+const FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+
+type PendingQueries = Arc<std::sync::Mutex<HashMap<u16, oneshot::Sender<Vec<u8>>>>>;
+// the open connections to each endpoint
+type StreamConnections = Arc<Mutex<Vec<Arc<StreamConnection>>>>;
+
+// Long-lived TCP, DoT and DoH connections to upstreams, shared by all queries.
+// Queries are pipelined on a TCP or DoT connection and matched to responses by message id
+// (RFC 7766), while DoH relies on HTTP/2 multiplexing or HTTP/1.1 keep-alive.
+pub struct ConnectionPool {
+    tls_connector: TlsConnector,
+    https_tls_connector: TlsConnector,
+    connections: std::sync::Mutex<HashMap<String, StreamConnections>>,
+    https_clients: std::sync::Mutex<HashMap<String, Client<HttpsConnector>>>,
+}
+
+impl ConnectionPool {
+    pub fn new() -> Self {
+        let root_store = match load_native_certs() {
+            Ok(store) => store,
+            Err((Some(store), e)) => {
+                println!("[Pool] Some native certificates are not loaded: {}", e);
+                store
+            }
+            Err((None, e)) => {
+                panic!("failed to load native certificates: {}", e);
+            }
+        };
+
+        let config = get_client_config(root_store.clone(), vec![b"dot".to_vec()]);
+        let https_config =
+            get_client_config(root_store, vec![b"h2".to_vec(), b"http/1.1".to_vec()]);
+
+        ConnectionPool {
+            tls_connector: TlsConnector::from(Arc::new(config)),
+            https_tls_connector: TlsConnector::from(Arc::new(https_config)),
+            connections: std::sync::Mutex::new(HashMap::new()),
+            https_clients: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    // every DoH endpoint has its own client, which dials the configured address
+    // while using the hostname for tls and http
+    pub fn get_https_client(
+        &self,
+        upstream: &DNSServerUpstream,
+        endpoint: &UpstreamEndpoint,
+    ) -> Client<HttpsConnector> {
+        self.https_clients
+            .lock()
+            .unwrap()
+            .entry(endpoint.to_string())
+            .or_insert_with(|| {
+                let connector = HttpsConnector {
+                    tls_connector: self.https_tls_connector.clone(),
+                    address: endpoint.socket_addr(),
+                    hostname: endpoint.hostname.clone(),
+                    connect_timeout: upstream.connect_timeout(),
+                };
+                Client::builder()
+                    .pool_idle_timeout(upstream.idle_timeout())
+                    .pool_max_idle_per_host(upstream.pool_size())
+                    .build(connector)
+            })
+            .clone()
+    }
+
+    pub async fn query(
+        &self,
+        message: &Message<Vec<u8>>,
+        upstream: &DNSServerUpstream,
+        endpoint: &UpstreamEndpoint,
+    ) -> Result<Message<Vec<u8>>, Error> {
+        let connection = self.get_connection(upstream, endpoint).await?;
+        match connection.query(message, upstream.read_timeout()).await {
+            // the server may have closed an idle connection right before we sent the query,
+            // retry once on a fresh connection
+            Err(e) if e.kind() == ErrorKind::ConnectionAborted => {
+                let connection = self.get_connection(upstream, endpoint).await?;
+                connection.query(message, upstream.read_timeout()).await
+            }
+            result => result,
+        }
+    }
+
+    async fn get_connection(
+        &self,
+        upstream: &DNSServerUpstream,
+        endpoint: &UpstreamEndpoint,
+    ) -> Result<Arc<StreamConnection>, Error> {
+        let slot = self
+            .connections
+            .lock()
+            .unwrap()
+            .entry(endpoint.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(vec![])))
+            .clone();
+
+        // connecting happens with the slot locked, so that concurrent queries
+        // do not open more connections than needed
+        let mut connections = slot.lock().await;
+        connections.retain(|connection| !connection.is_closed());
+
+        let idlest = connections
+            .iter()
+            .min_by_key(|connection| connection.pending_count())
+            .cloned();
+        if let Some(connection) = &idlest {
+            if connection.pending_count() == 0 || connections.len() >= upstream.pool_size() {
+                return Ok(connection.clone());
+            }
+        }
+
+        let connection = Arc::new(self.connect(upstream, endpoint).await?);
+        connections.push(connection.clone());
+        Ok(connection)
+    }
+
+    async fn connect(
+        &self,
+        upstream: &DNSServerUpstream,
+        endpoint: &UpstreamEndpoint,
+    ) -> Result<StreamConnection, Error> {
+        let idle_timeout = upstream.idle_timeout();
+        let connect = async {
+            let socket = TcpStream::connect(endpoint.socket_addr()).await?;
+            socket.set_nodelay(true)?;
+            if endpoint.protocol != QueryType::DoT {
+                return Ok::<_, Error>(StreamConnection::new(socket, idle_timeout));
+            }
+            let server_name = DNSNameRef::try_from_ascii_str(&endpoint.hostname)
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, "[Pool] Invalid hostname."))?;
+            let socket = self.tls_connector.connect(server_name, socket).await?;
+            Ok(StreamConnection::new(socket, idle_timeout))
+        };
+        timeout(upstream.connect_timeout(), connect).await?
+    }
+}
+
+struct StreamConnection {
+    sender: UnboundedSender<Vec<u8>>,
+    pending: PendingQueries,
+    next_id: AtomicU16,
+    closed: Arc<AtomicBool>,
+}
+
+impl StreamConnection {
+    fn new<S>(stream: S, idle_timeout: Duration) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, mut writer) = split(stream);
+        let (sender, mut receiver) = unbounded_channel::<Vec<u8>>();
+        let (close_sender, mut close_receiver) = oneshot::channel::<()>();
+        let pending: PendingQueries = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    buf = receiver.recv() => {
+                        let buf = match buf {
+                            Some(buf) => buf,
+                            None => break,
+                        };
+                        // tls keeps what the socket did not take yet until it is flushed
+                        if writer.write_all(&buf).await.is_err() || writer.flush().await.is_err() {
+                            break;
+                        }
+                    }
+                    _ = &mut close_receiver => break,
+                }
+            }
+            writer.shutdown().await.ok();
+        });
+
+        let reader_pending = pending.clone();
+        let reader_closed = closed.clone();
+        tokio::spawn(async move {
+            let mut reader = FramedReader::new(BufReader::new(reader), MAX_FRAME_SIZE);
+            // the server may ask us to keep the connection for a different time (RFC 7828)
+            let mut idle_timeout = idle_timeout;
+            loop {
+                // waits for data without consuming it, so an expired wait never
+                // leaves a partially read message behind
+                match timeout(idle_timeout, reader.get_mut().fill_buf()).await {
+                    Ok(Ok(buf)) if !buf.is_empty() => {}
+                    Ok(_) => break,
+                    Err(_) => {
+                        if reader_pending.lock().unwrap().is_empty() {
+                            break;
+                        }
+                        continue;
+                    }
+                }
+                let buf = match timeout(FRAME_TIMEOUT, reader.read_frame()).await {
+                    Ok(Ok(buf)) => buf,
+                    Ok(Err(FrameError::Closed)) => break,
+                    Ok(Err(e)) => {
+                        println!("[Pool] Bad response frame ({}), connection closed.", e);
+                        break;
+                    }
+                    Err(_) => {
+                        println!("[Pool] Incomplete response frame, connection closed.");
+                        break;
+                    }
+                };
+
+                if let Some(keepalive) = get_tcp_keepalive(&buf) {
+                    idle_timeout = keepalive;
+                }
+
+                let id = u16::from_be_bytes([buf[0], buf[1]]);
+                if let Some(sender) = reader_pending.lock().unwrap().remove(&id) {
+                    sender.send(buf).ok();
+                }
+            }
+
+            reader_closed.store(true, Ordering::Relaxed);
+            // wakes up all waiting queries with an error
+            reader_pending.lock().unwrap().clear();
+            close_sender.send(()).ok();
+        });
+
+        StreamConnection {
+            sender,
+            pending,
+            next_id: AtomicU16::new(0),
+            closed,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    fn pending_count(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    async fn query(
+        &self,
+        message: &Message<Vec<u8>>,
+        read_timeout: Duration,
+    ) -> Result<Message<Vec<u8>>, Error> {
+        let (sender, receiver) = oneshot::channel();
+
+        // queries from different clients may carry the same id, so every query
+        // gets an id that is unique on this connection, and the original one is restored later
+        let id = {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.len() >= u16::MAX as usize {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    "[Pool] Too many pending queries.",
+                ));
+            }
+            let mut id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            while pending.contains_key(&id) {
+                id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            }
+            pending.insert(id, sender);
+            id
+        };
+
+        let mut buf = message.as_octets().clone();
+        let origin_id = [buf[0], buf[1]];
+        buf[..2].copy_from_slice(&id.to_be_bytes());
+
+        if self.is_closed() || self.sender.send(get_wrapped_buf(&buf)).is_err() {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(Error::new(
+                ErrorKind::ConnectionAborted,
+                "[Pool] Connection closed.",
+            ));
+        }
+
+        let mut buf = match timeout(read_timeout, receiver).await {
+            Ok(Ok(buf)) => buf,
+            Ok(Err(_)) => {
+                return Err(Error::new(
+                    ErrorKind::ConnectionAborted,
+                    "[Pool] Connection closed before response.",
+                ));
+            }
+            Err(e) => {
+                self.pending.lock().unwrap().remove(&id);
+                return Err(e.into());
+            }
+        };
+        buf[..2].copy_from_slice(&origin_id);
+
+        Message::from_octets(buf)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "[Pool] Invalid response."))
+    }
+}
+
+fn get_client_config(root_store: RootCertStore, alpn_protocols: Vec<Vec<u8>>) -> ClientConfig {
+    let mut config = ClientConfig::new();
+    config.root_store = root_store;
+    config.enable_sni = true;
+    config.enable_early_data = true;
+    config.versions = vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2];
+    config.set_protocols(&alpn_protocols);
+    config
+}
+
+#[derive(Clone)]
+pub struct HttpsConnector {
+    tls_connector: TlsConnector,
+    address: String,
+    hostname: String,
+    connect_timeout: Duration,
+}
+
+impl Service<Uri> for HttpsConnector {
+    type Response = HttpsStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<HttpsStream, Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _: Uri) -> Self::Future {
+        let connector = self.clone();
+        Box::pin(async move {
+            let connect = async {
+                let socket = TcpStream::connect(&connector.address).await?;
+                socket.set_nodelay(true)?;
+                let server_name = DNSNameRef::try_from_ascii_str(&connector.hostname)
+                    .map_err(|_| Error::new(ErrorKind::InvalidInput, "[Pool] Invalid hostname."))?;
+                let socket = connector.tls_connector.connect(server_name, socket).await?;
+                Ok::<_, Error>(HttpsStream(socket))
+            };
+            timeout(connector.connect_timeout, connect).await?
+        })
+    }
+}
+
+pub struct HttpsStream(TlsStream<TcpStream>);
+
+impl Connection for HttpsStream {
+    // tells hyper to speak HTTP/2 when the server agreed on it by ALPN
+    fn connected(&self) -> Connected {
+        let (_, session) = self.0.get_ref();
+        if session.get_alpn_protocol() == Some(b"h2") {
+            Connected::new().negotiated_h2()
+        } else {
+            Connected::new()
+        }
+    }
+}
+
+impl AsyncRead for HttpsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for HttpsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+// the idle timeout an upstream asks for by edns-tcp-keepalive
+fn get_tcp_keepalive(buf: &[u8]) -> Option<Duration> {
+    let message = Message::from_octets(buf.to_vec()).ok()?;
+    let opt = message.opt()?;
+    let keepalive = opt.as_opt().iter::<TcpKeepalive>().find_map(|o| o.ok())?;
+    // in units of 100 milliseconds
+    Some(Duration::from_millis(keepalive.timeout() as u64 * 100))
+}
@@ -0,0 +1,135 @@
+use super::{
+    health::HealthRegistry, lookup_with_health, pool::ConnectionPool, utils::QueryResponse,
+};
+use crate::dns::settings::{
+    DNSServerUpstream, DNSUpstreamGroup, UpstreamEndpoint, UpstreamStrategy,
+};
+use domain::base::Message;
+use futures::{
+    future::{pending, select_ok},
+    stream::FuturesUnordered,
+    Future, StreamExt,
+};
+use std::{
+    cmp::Ordering,
+    io::{Error, ErrorKind},
+    pin::Pin,
+    sync::Arc,
+};
+use tokio::time::{sleep, Duration};
+
+type Query<'a> = Pin<Box<dyn Future<Output = Result<QueryResponse, Error>> + Send + 'a>>;
+
+// query the endpoints of a group the way its strategy says
+pub async fn query_group<'a, F>(
+    name: &str,
+    group: &DNSUpstreamGroup,
+    get_request: &F,
+    endpoints: Vec<(&'a DNSServerUpstream, &'a UpstreamEndpoint)>,
+    pool: &'a Arc<ConnectionPool>,
+    health: &'a Arc<HealthRegistry>,
+) -> Result<QueryResponse, Error>
+where
+    F: Fn(&DNSServerUpstream, &UpstreamEndpoint) -> Message<Vec<u8>>,
+{
+    // ejected endpoints are skipped, unless there is nothing else to ask
+    let available: Vec<_> = endpoints
+        .iter()
+        .filter(|(_, endpoint)| health.is_available(endpoint))
+        .cloned()
+        .collect();
+    let mut endpoints = if available.is_empty() {
+        endpoints
+    } else {
+        available
+    };
+    if endpoints.is_empty() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("no upstream in group {}", name),
+        ));
+    }
+
+    let strategy = group.strategy();
+    match strategy {
+        UpstreamStrategy::Race | UpstreamStrategy::Fallback => {}
+        UpstreamStrategy::Hedged | UpstreamStrategy::Fastest => {
+            // measured endpoints first, the fastest of them before the others
+            let mut scored: Vec<_> = endpoints
+                .into_iter()
+                .map(|(upstream, endpoint)| {
+                    (health.get_health(endpoint).score(), upstream, endpoint)
+                })
+                .collect();
+            scored.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+            endpoints = scored
+                .into_iter()
+                .map(|(_, upstream, endpoint)| (upstream, endpoint))
+                .collect();
+        }
+        UpstreamStrategy::RoundRobin => {
+            let round = health.next_round(name) % endpoints.len();
+            endpoints.rotate_left(round);
+        }
+    }
+
+    let queries: Vec<Query<'a>> = endpoints
+        .into_iter()
+        .map(|(upstream, endpoint)| {
+            let request = get_request(upstream, endpoint);
+            Box::pin(
+                async move { lookup_with_health(request, upstream, endpoint, pool, health).await },
+            ) as Query<'a>
+        })
+        .collect();
+
+    match strategy {
+        UpstreamStrategy::Race => Ok(select_ok(queries).await?.0),
+        UpstreamStrategy::Hedged => query_hedged(queries, Some(group.hedge_delay())).await,
+        _ => query_hedged(queries, None).await,
+    }
+}
+
+// start queries one by one, the next one as soon as a previous one fails or `delay` passes,
+// without a delay it only waits for failures
+async fn query_hedged(
+    queries: Vec<Query<'_>>,
+    delay: Option<Duration>,
+) -> Result<QueryResponse, Error> {
+    let mut waiting = queries.into_iter();
+    let mut running = FuturesUnordered::new();
+    let mut last_error = Error::new(ErrorKind::NotFound, "no upstream to query");
+    loop {
+        if running.is_empty() {
+            match waiting.next() {
+                Some(query) => running.push(query),
+                None => return Err(last_error),
+            }
+        }
+
+        let delay = delay.filter(|_| waiting.len() > 0);
+        let hedge = async move {
+            match delay {
+                Some(delay) => sleep(delay).await,
+                None => pending().await,
+            }
+        };
+
+        tokio::select! {
+            Some(result) = running.next() => match result {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    last_error = e;
+                    if let Some(query) = waiting.next() {
+                        running.push(query);
+                    }
+                }
+            },
+            _ = hedge => {
+                if let Some(query) = waiting.next() {
+                    running.push(query);
+                }
+            }
+        }
+    }
+}
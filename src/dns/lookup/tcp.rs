@@ -1,23 +1,16 @@
-use super::utils::{get_wrapped_packet, is_valid_response, QueryResponse};
+use super::pool::ConnectionPool;
+use super::utils::{is_valid_response, QueryResponse};
+use crate::dns::settings::{DNSServerUpstream, UpstreamEndpoint};
 use domain::base::Message;
 use std::io::{Error, ErrorKind};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::{net::TcpStream, time::timeout, time::Duration};
 
 pub async fn lookup_tcp(
     message: &Message<Vec<u8>>,
-    remote_addr: &std::string::String,
+    upstream: &DNSServerUpstream,
+    endpoint: &UpstreamEndpoint,
+    pool: &ConnectionPool,
 ) -> Result<QueryResponse, Error> {
-    let mut socket = TcpStream::connect(format!("{}:{}", remote_addr, 53)).await?;
-
-    let packet = get_wrapped_packet(message);
-    socket.write(&packet).await?;
-
-    let mut packet = Vec::with_capacity(1024);
-    timeout(Duration::from_millis(2000), socket.read_buf(&mut packet)).await??;
-
-    // tips: here omits checking packet size
-    let ret_message = Message::from_octets(packet[2..].to_vec()).unwrap();
+    let ret_message = pool.query(message, upstream, endpoint).await?;
 
     if is_valid_response(&ret_message) {
         return Ok(QueryResponse::TCP(ret_message));
@@ -25,6 +18,6 @@ pub async fn lookup_tcp(
 
     Err(Error::new(
         ErrorKind::InvalidData,
-        "[TCP] Packet size checking failed.".to_string(),
+        "[TCP] Invalid response.".to_string(),
     ))
 }
@@ -1,33 +1,168 @@
-use super::utils::{is_valid_response_udp, QueryResponse};
+use super::pool::ConnectionPool;
+use super::utils::{
+    get_udp_payload_size, is_matching_response, is_valid_response, is_valid_response_udp,
+    QueryResponse, QueryType,
+};
+use crate::dns::settings::{DNSServerUpstream, UpstreamEndpoint};
 use domain::base::Message;
-use std::{io::Error, net::SocketAddr};
-use tokio::{net::UdpSocket, time::timeout};
+use rand::Rng;
+use std::{
+    io::{Error, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use tokio::{
+    net::UdpSocket,
+    time::{timeout_at, Instant},
+};
+
+// resolvers cap ttls at a week or so, e.g. bind `max-cache-ttl`
+const MAX_ANSWER_TTL: u32 = 7 * 24 * 3600;
 
 pub async fn lookup_udp(
     message: &Message<Vec<u8>>,
-    remote_addr: &std::string::String,
+    upstream: &DNSServerUpstream,
+    endpoint: &UpstreamEndpoint,
+    pool: &ConnectionPool,
 ) -> Result<QueryResponse, Error> {
-    let remote_addr: SocketAddr = format!("{}:{}", remote_addr, 53).parse().unwrap();
-    let local_addr: SocketAddr = if remote_addr.is_ipv4() {
-        "0.0.0.0:0".parse().unwrap()
-    } else {
-        "[::]:0".parse().unwrap()
-    };
-
-    let socket = UdpSocket::bind(local_addr).await?;
+    let remote_addr: SocketAddr = endpoint.socket_addr().parse().unwrap();
+    let socket = bind_random_port(&remote_addr).await?;
     socket.connect(remote_addr).await?;
     socket.send(message.as_octets()).await?;
+    let sent_at = Instant::now();
 
-    let duration = tokio::time::Duration::from_millis(2000);
+    // the buffer size we told the upstream, a larger datagram means it ignored that
+    let payload_size = get_udp_payload_size(message);
+
+    // skipped responses do not extend the waiting time
+    let deadline = Instant::now() + upstream.read_timeout();
     let mut ret_message;
+    // where answers are injected, an invalid one may be forged as well and is only returned
+    // when nothing else comes, otherwise a failure is not worth waiting for anything else
+    let guards_injection = upstream.injection_wait().is_some() || upstream.udp_require_dnssec_ok();
+    let mut invalid_error = None;
     loop {
-        let mut buf = vec![0u8; 1024];
-        let size = timeout(duration, socket.recv(&mut buf)).await??;
-        ret_message = Message::from_octets(buf[..size].to_vec()).unwrap();
-        if is_valid_response_udp(&ret_message) {
+        let mut buf = vec![0u8; 65535];
+        let size = match timeout_at(deadline, socket.recv(&mut buf)).await {
+            Ok(result) => result?,
+            Err(e) => return Err(invalid_error.unwrap_or_else(|| e.into())),
+        };
+        ret_message = match Message::from_octets(buf[..size].to_vec()) {
+            Ok(message) => message,
+            // too short to be a dns message
+            Err(_) => continue,
+        };
+        // forged answers with a guessed id or question are dropped, the real one may follow
+        if !is_matching_response(message, &ret_message, upstream.case_randomization()) {
+            continue;
+        }
+        if ret_message.header().tc() || size > payload_size {
+            return lookup_tcp_fallback(message, upstream, endpoint, pool).await;
+        }
+        if !is_valid_response(&ret_message) {
+            let error = Error::new(ErrorKind::InvalidData, "[UDP] Invalid response.");
+            if !guards_injection {
+                return Err(error);
+            }
+            invalid_error = Some(error);
+            continue;
+        }
+        // while answers not passing the anti-injection rule may be followed by the real one
+        if is_valid_response_udp(&ret_message, upstream.udp_require_dnssec_ok()) {
             break;
         }
     }
 
+    // injected answers race ahead of the real one, which still arrives shortly after,
+    // so a suspicious answer is only taken when nothing better follows
+    if let Some(wait) = upstream.injection_wait() {
+        let mut suspicion = get_injection_suspicion(message, &ret_message, sent_at, upstream);
+        let wait_deadline = deadline.min(Instant::now() + wait);
+        while let Some(reason) = suspicion {
+            let mut buf = vec![0u8; 65535];
+            let size = match timeout_at(wait_deadline, socket.recv(&mut buf)).await {
+                Ok(Ok(size)) => size,
+                _ => break,
+            };
+            let later_message = match Message::from_octets(buf[..size].to_vec()) {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+            if is_matching_response(message, &later_message, upstream.case_randomization())
+                && !later_message.header().tc()
+                && is_valid_response_udp(&later_message, upstream.udp_require_dnssec_ok())
+            {
+                println!(
+                    "[UDP] Answer from {} {} and another followed, taken as injected.",
+                    endpoint, reason
+                );
+                suspicion = get_injection_suspicion(message, &later_message, sent_at, upstream);
+                ret_message = later_message;
+            }
+        }
+    }
+
     Ok(QueryResponse::UDP(ret_message))
 }
+
+// Why an answer looks injected on the path, if it does. Injectors sit closer than the
+// upstream and answer faster than it could, and they seldom echo EDNS or pick sane ttls.
+fn get_injection_suspicion(
+    message: &Message<Vec<u8>>,
+    ret_message: &Message<Vec<u8>>,
+    sent_at: Instant,
+    upstream: &DNSServerUpstream,
+) -> Option<&'static str> {
+    if sent_at.elapsed() < upstream.injection_min_rtt() {
+        return Some("arrived too fast");
+    }
+    if message.opt().is_some() && ret_message.opt().is_none() {
+        return Some("dropped EDNS");
+    }
+    let answers = ret_message.answer().ok()?;
+    for record in answers {
+        match record {
+            Ok(record) if record.ttl() <= MAX_ANSWER_TTL => {}
+            _ => return Some("has an invalid ttl"),
+        }
+    }
+    None
+}
+
+// a random source port makes forged answers harder to guess, along with the random id
+async fn bind_random_port(remote_addr: &SocketAddr) -> Result<UdpSocket, Error> {
+    let ip: IpAddr = if remote_addr.is_ipv4() {
+        Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        Ipv6Addr::UNSPECIFIED.into()
+    };
+    for _ in 0..8 {
+        let port = rand::thread_rng().gen_range(1024..=65535);
+        if let Ok(socket) = UdpSocket::bind(SocketAddr::new(ip, port)).await {
+            return Ok(socket);
+        }
+    }
+    // every try hit a port in use, leave it to the system
+    UdpSocket::bind(SocketAddr::new(ip, 0)).await
+}
+
+// retry a truncated query over tcp to the same upstream (RFC 7766 section 5)
+async fn lookup_tcp_fallback(
+    message: &Message<Vec<u8>>,
+    upstream: &DNSServerUpstream,
+    endpoint: &UpstreamEndpoint,
+    pool: &ConnectionPool,
+) -> Result<QueryResponse, Error> {
+    let mut tcp_endpoint = endpoint.clone();
+    tcp_endpoint.protocol = QueryType::TCP;
+
+    let ret_message = pool.query(message, upstream, &tcp_endpoint).await?;
+
+    if is_valid_response(&ret_message) {
+        return Ok(QueryResponse::TCP(ret_message));
+    }
+
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        "[UDP] Invalid response on tcp fallback.".to_string(),
+    ))
+}
@@ -1,8 +1,14 @@
-use crate::router::GeoIP;
-use domain::{base::Message, rdata::AllRecordData};
+use crate::router::{cidr::CidrSet, GeoIP};
+use domain::{
+    base::{
+        iana::{Opcode, Rcode},
+        Message, ParsedDname,
+    },
+    rdata::AllRecordData,
+};
 use std::{net::IpAddr, sync::Arc};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum QueryType {
     UDP,
     TCP,
@@ -10,6 +16,7 @@ pub enum QueryType {
     DoH,
     Custom,
     Cache,
+    Error,
 }
 
 pub enum QueryResponse {
@@ -19,73 +26,117 @@ pub enum QueryResponse {
     DoH(Message<Vec<u8>>),
     Custom(Message<Vec<u8>>),
     Cache(Message<Vec<u8>>),
+    Error(Message<Vec<u8>>),
 }
 
-// add 2-byte head to packet
-pub fn get_wrapped_packet(message: &Message<Vec<u8>>) -> Vec<u8> {
-    let buf = &mut message.as_octets().clone();
-    let mut packet = Vec::with_capacity(2 + buf.len());
-    packet.push((buf.len() >> 8) as u8);
-    packet.push(buf.len() as u8);
-    packet.append(buf);
-    packet
+// whether any address in A or AAAA records of the answer is at home
+pub fn is_home_site(message: &Message<Vec<u8>>, geoip: Arc<GeoIP>) -> bool {
+    let answers = match message.answer() {
+        Ok(answers) => answers.limit_to::<AllRecordData<_, _>>(),
+        Err(_) => return false,
+    };
+    for answer in answers.flatten() {
+        let ip = match answer.data() {
+            AllRecordData::A(a) => IpAddr::V4(a.addr()),
+            AllRecordData::Aaaa(aaaa) => IpAddr::V6(aaaa.addr()),
+            _ => continue,
+        };
+        if geoip.is_home(&ip) {
+            return true;
+        }
+    }
+    false
 }
 
-pub fn is_china_site(message: &Message<Vec<u8>>, geoip: Arc<GeoIP>) -> bool {
-    // let message = Message::from_octets(buf).unwrap();
-    let answers = message.answer().unwrap().limit_to::<AllRecordData<_, _>>();
-    let mut is_china = false;
-    for answer in answers {
-        if let Ok(answer_first_china) = answer {
-            let rtype = answer_first_china.rtype().to_string();
-
-            if rtype != "A" {
-                continue;
-            }
-
-            let ip = answer_first_china.data().to_string();
-            let ip: IpAddr = match ip.parse() {
-                Ok(ip) => ip,
-                Err(_) => return false,
-            };
-
-            if rtype.starts_with('A') && geoip.lookup_country_code(&ip) == "CN" {
-                is_china = true;
-                break;
-            }
+// whether any address in A or AAAA records of the answer is a known bogus one
+pub fn has_bogus_ip(message: &Message<Vec<u8>>, bogus_set: &CidrSet) -> bool {
+    let answers = match message.answer() {
+        Ok(answers) => answers.limit_to::<AllRecordData<_, _>>(),
+        Err(_) => return false,
+    };
+    for answer in answers.flatten() {
+        let ip = match answer.data() {
+            AllRecordData::A(a) => IpAddr::V4(a.addr()),
+            AllRecordData::Aaaa(aaaa) => IpAddr::V6(aaaa.addr()),
+            _ => continue,
+        };
+        if bogus_set.contains(&ip) {
+            return true;
         }
     }
-
-    is_china
+    false
 }
 
-pub fn is_valid_response_udp(message: &Message<Vec<u8>>) -> bool {
-    if !message.is_error() {
-        if message.additional().is_ok() && message.additional().unwrap().count() != 0 {
-            if (message.answer().is_ok() && message.answer().unwrap().count() != 0)
-                || (message.authority().is_ok() && message.authority().unwrap().count() != 0)
-            {
-                if message.opt().is_some() && message.opt().unwrap().dnssec_ok() {
-                    return true;
-                }
-            }
-        }
+// whether a response answers the query, by id and question,
+// with `exact_case` the name must also keep the case it was sent with (0x20 encoding)
+pub fn is_matching_response(
+    request: &Message<Vec<u8>>,
+    response: &Message<Vec<u8>>,
+    exact_case: bool,
+) -> bool {
+    if request.header().id() != response.header().id() {
         return false;
     }
-    true
+    let (question, answered) = match (request.first_question(), response.first_question()) {
+        (Some(question), Some(answered)) => (question, answered),
+        _ => return false,
+    };
+    let name = question.qname().to_string();
+    let answered_name = answered.qname().to_string();
+    let is_same_name = if exact_case {
+        name == answered_name
+    } else {
+        name.eq_ignore_ascii_case(&answered_name)
+    };
+    is_same_name && question.qtype() == answered.qtype() && question.qclass() == answered.qclass()
 }
+
+// the udp payload size a query advertises, at least 512 bytes (RFC 6891 6.2.5)
+pub fn get_udp_payload_size(message: &Message<Vec<u8>>) -> usize {
+    match message.opt() {
+        Some(opt) => (opt.udp_payload_size() as usize).max(512),
+        None => 512,
+    }
+}
+
+// A well-formed answer, which includes NODATA and NXDOMAIN. Server failures and refusals
+// are not, so that other upstreams get the chance to answer.
 pub fn is_valid_response(message: &Message<Vec<u8>>) -> bool {
-    if !message.is_error() {
-        if message.additional().is_ok() && message.additional().unwrap().count() != 0 {
-            if (message.answer().is_ok() && message.answer().unwrap().count() != 0)
-                || (message.authority().is_ok() && message.authority().unwrap().count() != 0)
-            {
-                return true;
-            }
-        }
+    let header = message.header();
+    if !header.qr() || header.opcode() != Opcode::Query {
+        return false;
+    }
+    if header.rcode() != Rcode::NoError && header.rcode() != Rcode::NXDomain {
+        return false;
+    }
+
+    // every record has to parse, its data as well as its header
+    let sections = [message.answer(), message.authority(), message.additional()];
+    sections.iter().all(|section| match *section {
+        Ok(section) => section
+            .limit_to::<AllRecordData<_, ParsedDname<_>>>()
+            .all(|record| record.is_ok()),
+        Err(_) => false,
+    })
+}
+
+// With `require_dnssec_ok`, udp answers must also carry records and an OPT record with
+// the DO bit, which answers injected on the path usually lack.
+pub fn is_valid_response_udp(message: &Message<Vec<u8>>, require_dnssec_ok: bool) -> bool {
+    if !is_valid_response(message) {
         return false;
     }
-    true
+    if !require_dnssec_ok {
+        return true;
+    }
+
+    let has_records =
+        message.header_counts().ancount() != 0 || message.header_counts().nscount() != 0;
+    let dnssec_ok = match message.opt() {
+        Some(opt) => opt.dnssec_ok(),
+        None => false,
+    };
+    has_records && dnssec_ok
 }
 
 pub fn get_message_from_response(response: QueryResponse) -> (Message<Vec<u8>>, QueryType) {
@@ -96,6 +147,7 @@ pub fn get_message_from_response(response: QueryResponse) -> (Message<Vec<u8>>,
         QueryResponse::DoH(message) => (message, QueryType::DoH),
         QueryResponse::Custom(message) => (message, QueryType::Custom),
         QueryResponse::Cache(message) => (message, QueryType::Cache),
+        QueryResponse::Error(message) => (message, QueryType::Error),
     }
 }
 
@@ -107,5 +159,6 @@ pub fn get_message_from_response_ref(response: &QueryResponse) -> (&Message<Vec<
         QueryResponse::DoH(message) => (message, QueryType::DoH),
         QueryResponse::Custom(message) => (message, QueryType::Custom),
         QueryResponse::Cache(message) => (message, QueryType::Cache),
+        QueryResponse::Error(message) => (message, QueryType::Error),
     }
 }
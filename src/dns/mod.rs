@@ -3,6 +3,12 @@ mod utils;
 mod settings;
 mod lookup;
 mod custom;
+mod domains;
+mod ecs;
 mod cache;
+mod tls;
+mod https;
+mod ede;
+mod frame;
 
 pub use server::*;
\ No newline at end of file
@@ -1,42 +1,91 @@
 use super::{
-    cache::lookup_cache,
+    cache::{lookup_cache, new_cache, save_cache, AnswerCache, CacheState, Prefetcher},
     custom::lookup_custom,
-    lookup::{batch_query, utils::get_message_from_response},
-    settings::DNSSettings,
-    utils::get_request_message,
+    ecs::ClientSubnetPolicy,
+    ede::{ExtendedError, ExtendedErrorCode},
+    frame::{get_wrapped_buf, FrameError, FramedReader},
+    https::{get_doh_error_response, get_doh_query, get_doh_response},
+    lookup::{
+        batch_query,
+        health::HealthRegistry,
+        pool::ConnectionPool,
+        utils::{
+            get_message_from_response, get_message_from_response_ref, get_udp_payload_size,
+            QueryResponse, QueryType,
+        },
+    },
+    settings::{DNSServerUpstream, DNSSettings, DNSTlsListener, UpstreamEndpoint},
+    tls::load_server_config,
+    utils::{
+        check_request_message, get_error_message, get_request_message, get_truncated_message,
+        restore_response, RequestOptions,
+    },
 };
 use crate::router::GeoIP;
 use core::panic;
-use domain::{base::Message, rdata::AllRecordData};
-use futures::future::try_join;
+use domain::{
+    base::{iana::Rcode, Message},
+    rdata::AllRecordData,
+};
+use futures::future::try_join4;
 use glob::Pattern;
-use redis::{aio::Connection, AsyncCommands};
+use hyper::{server::conn::Http, service::service_fn, Body, Request, Response, StatusCode};
 use std::io::{Error, ErrorKind};
-use std::sync::Arc;
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 use tokio::{
-    net::{TcpListener, TcpStream, UdpSocket},
-    sync::Mutex,
+    io::{split, AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    sync::{
+        mpsc::{unbounded_channel, UnboundedSender},
+        oneshot,
+    },
+    time::{timeout, Duration},
 };
+use tokio_rustls::TlsAcceptor;
 
+#[derive(Clone)]
 pub struct DNSServer {
     server_udp: Arc<UdpSocket>,
     server_tcp: Arc<TcpListener>,
-    redis: Arc<Mutex<Option<Connection>>>,
+    server_dot: Option<(Arc<TcpListener>, TlsAcceptor)>,
+    server_doh: Option<(Arc<TcpListener>, TlsAcceptor)>,
+    cache: Option<Arc<AnswerCache>>,
+    prefetcher: Arc<Prefetcher>,
     geoip: Arc<GeoIP>,
+    pool: Arc<ConnectionPool>,
+    health: Arc<HealthRegistry>,
+    ecs: Arc<ClientSubnetPolicy>,
     settings: Arc<DNSSettings>,
     custom_patterns: Arc<Vec<(Pattern, String)>>,
 }
 
 enum TargetType {
     UDP(String),
-    TCP(TcpStream, String),
+    TCP(UnboundedSender<Vec<u8>>, String),
+    DoT(UnboundedSender<Vec<u8>>, String),
+    DoH(oneshot::Sender<Vec<u8>>, String),
+}
+
+impl TargetType {
+    fn source(&self) -> &String {
+        match self {
+            TargetType::UDP(addr) => addr,
+            TargetType::TCP(_, addr) => addr,
+            TargetType::DoT(_, addr) => addr,
+            TargetType::DoH(_, addr) => addr,
+        }
+    }
 }
 
 impl DNSServer {
     pub async fn new() -> Self {
         let geoip = Arc::new(GeoIP::new().await);
         let settings = Arc::new(Self::load_settings().await);
-        let custom_patterns = Arc::new(Self::load_patterns(Self::load_settings().await));
+        let custom_patterns = Arc::new(Self::load_patterns(&settings));
         let server_udp =
             match UdpSocket::bind(format!("{}:{}", settings.listen_ip, settings.listen_port)).await
             {
@@ -70,37 +119,92 @@ impl DNSServer {
         let server_udp = Arc::new(server_udp);
         let server_tcp = Arc::new(server_tcp);
 
-        let mut redis = None;
-        if let Some(redis_server) = &settings.redis_server {
-            let client = redis::Client::open(redis_server.clone()).unwrap();
-            redis = match client.get_async_connection().await {
-                Ok(server) => {
-                    println!("Using redis at {} as cache.", redis_server);
-                    Some(server)
-                }
-                Err(e) => {
-                    panic!("error on redis instance: {}", e);
-                }
-            };
+        let mut server_dot = None;
+        if let Some(dot) = &settings.dot {
+            server_dot =
+                Some(Self::bind_tls(&settings.listen_ip, dot, vec![b"dot".to_vec()], "tls").await);
+        }
+
+        let mut server_doh = None;
+        if let Some(doh) = &settings.doh {
+            server_doh = Some(
+                Self::bind_tls(
+                    &settings.listen_ip,
+                    &doh.listener,
+                    vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+                    "https",
+                )
+                .await,
+            );
         }
 
-        let redis = Arc::new(Mutex::new(redis));
+        let cache = new_cache(&settings).await;
+        let prefetcher = Arc::new(Prefetcher::new(&settings));
+        let pool = Arc::new(ConnectionPool::new());
+
+        let health = Arc::new(HealthRegistry::new());
+        tokio::spawn(
+            health
+                .clone()
+                .keep_probing(settings.upstreams.clone(), pool.clone()),
+        );
+
+        let ecs = Arc::new(ClientSubnetPolicy::new(&settings));
+        if ecs.is_auto() {
+            tokio::spawn(ecs.clone().keep_detected());
+        }
 
         DNSServer {
             server_udp,
             server_tcp,
+            server_dot,
+            server_doh,
             geoip,
-            redis,
+            cache,
+            prefetcher,
+            pool,
+            health,
+            ecs,
             settings,
             custom_patterns,
         }
     }
 
-    pub fn load_patterns(settings: DNSSettings) -> Vec<(Pattern, String)> {
+    async fn bind_tls(
+        listen_ip: &String,
+        listener: &DNSTlsListener,
+        alpn_protocols: Vec<Vec<u8>>,
+        scheme: &str,
+    ) -> (Arc<TcpListener>, TlsAcceptor) {
+        let config =
+            match load_server_config(&listener.cert_file, &listener.key_file, alpn_protocols).await
+            {
+                Ok(config) => config,
+                Err(e) => {
+                    panic!("error on loading {} certificate: {}", scheme, e);
+                }
+            };
+        let server =
+            match TcpListener::bind(format!("{}:{}", listen_ip, listener.listen_port)).await {
+                Ok(server) => {
+                    println!(
+                        "DNS service now is serving on {}://{}:{}",
+                        scheme, listen_ip, listener.listen_port
+                    );
+                    server
+                }
+                Err(e) => {
+                    panic!("error on {} listening: {}", scheme, e);
+                }
+            };
+        (Arc::new(server), TlsAcceptor::from(Arc::new(config)))
+    }
+
+    pub fn load_patterns(settings: &DNSSettings) -> Vec<(Pattern, String)> {
         let mut patterns = vec![];
-        for (key, value) in settings.custom_hosts {
+        for (key, value) in &settings.custom_hosts {
             if let Ok(pattern) = Pattern::new(key.as_str()) {
-                patterns.push((pattern, value));
+                patterns.push((pattern, value.clone()));
             } else {
                 println!("[Custom] Failed to load pattern {}", key);
             }
@@ -112,140 +216,353 @@ impl DNSServer {
         let mut buf = vec![0u8; 4096];
         loop {
             let (size, addr) = self.server_udp.recv_from(&mut buf).await?;
-
-            let task = run_task(
-                self.server_udp.clone(),
-                self.server_tcp.clone(),
-                self.settings.clone(),
-                self.custom_patterns.clone(),
-                self.redis.clone(),
-                self.geoip.clone(),
-                TargetType::UDP(addr.to_string()),
-                buf[..size].to_vec(),
-            );
-
-            tokio::spawn(task);
+            self.spawn_task(TargetType::UDP(addr.to_string()), buf[..size].to_vec());
         }
         #[allow(unreachable_code)]
         Ok(())
     }
 
     pub async fn start_tcp(&self) -> Result<(), Error> {
-        let mut buf = vec![0u8; 4096];
         loop {
             let (socket, addr) = self.server_tcp.accept().await?;
-
-            socket.readable().await?;
-            let size = socket.try_read(&mut buf)?;
-
-            let task = run_task(
-                self.server_udp.clone(),
-                self.server_tcp.clone(),
-                self.settings.clone(),
-                self.custom_patterns.clone(),
-                self.redis.clone(),
-                self.geoip.clone(),
-                TargetType::TCP(socket, addr.to_string()),
-                buf[..size].to_vec(),
+            tokio::spawn(
+                self.clone()
+                    .serve_stream(socket, addr.to_string(), TargetType::TCP),
             );
+        }
+        #[allow(unreachable_code)]
+        Ok(())
+    }
+
+    pub async fn start_dot(&self) -> Result<(), Error> {
+        let (server_dot, acceptor) = match &self.server_dot {
+            Some(server_dot) => server_dot,
+            None => return Ok(()),
+        };
+        let handshake_timeout =
+            Duration::from_millis(self.settings.tcp_idle_timeout.unwrap_or(10000));
+        loop {
+            let (socket, addr) = server_dot.accept().await?;
+            let acceptor = acceptor.clone();
+            let server = self.clone();
+            tokio::spawn(async move {
+                match timeout(handshake_timeout, acceptor.accept(socket)).await {
+                    Ok(Ok(stream)) => {
+                        server
+                            .serve_stream(stream, addr.to_string(), TargetType::DoT)
+                            .await
+                    }
+                    Ok(Err(err)) => println!("[DoT] Failed to handshake with {}: {}", addr, err),
+                    Err(_) => println!("[DoT] Handshake with {} timed out.", addr),
+                }
+            });
+        }
+        #[allow(unreachable_code)]
+        Ok(())
+    }
 
-            tokio::spawn(task);
+    pub async fn start_doh(&self) -> Result<(), Error> {
+        let (server_doh, acceptor) = match &self.server_doh {
+            Some(server_doh) => server_doh,
+            None => return Ok(()),
+        };
+        let handshake_timeout =
+            Duration::from_millis(self.settings.tcp_idle_timeout.unwrap_or(10000));
+        loop {
+            let (socket, addr) = server_doh.accept().await?;
+            let acceptor = acceptor.clone();
+            let server = self.clone();
+            tokio::spawn(async move {
+                let stream = match timeout(handshake_timeout, acceptor.accept(socket)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(err)) => {
+                        println!("[DoH] Failed to handshake with {}: {}", addr, err);
+                        return;
+                    }
+                    Err(_) => {
+                        println!("[DoH] Handshake with {} timed out.", addr);
+                        return;
+                    }
+                };
+                let source = addr.to_string();
+                let service =
+                    service_fn(move |req| server.clone().serve_https_request(req, source.clone()));
+                // serves both HTTP/1.1 and HTTP/2, whichever the client speaks
+                if let Err(err) = Http::new().serve_connection(stream, service).await {
+                    println!("[DoH] Connection with {} closed: {}", addr, err);
+                }
+            });
         }
         #[allow(unreachable_code)]
         Ok(())
     }
 
+    async fn serve_https_request(
+        self,
+        req: Request<Body>,
+        source: String,
+    ) -> Result<Response<Body>, Infallible> {
+        let path = self
+            .settings
+            .doh
+            .as_ref()
+            .and_then(|doh| doh.path.clone())
+            .unwrap_or_else(|| "/dns-query".to_string());
+        let buf = match get_doh_query(req, path.as_str()).await {
+            Ok(buf) => buf,
+            Err(status) => return Ok(get_doh_error_response(status)),
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        self.spawn_task(TargetType::DoH(sender, source), buf);
+
+        match receiver.await {
+            Ok(buf) => Ok(get_doh_response(buf)),
+            // the task is dropped without answering
+            Err(_) => Ok(get_doh_error_response(StatusCode::BAD_GATEWAY)),
+        }
+    }
+
+    fn spawn_task(&self, target: TargetType, buf: Vec<u8>) {
+        tokio::spawn(run_task(self.clone(), target, buf));
+    }
+
+    // Serves length-prefixed queries (RFC 1035 4.2.2) on one connection until the client
+    // closes it or stays idle for too long. Every query runs in its own task and replies
+    // are written back as soon as they are ready, so they may be out of order (RFC 7766).
+    async fn serve_stream<S, F>(self, stream: S, source: String, get_target: F)
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+        F: Fn(UnboundedSender<Vec<u8>>, String) -> TargetType,
+    {
+        let (reader, mut writer) = split(stream);
+        // queries are small, anything larger is not worth reading
+        let mut reader = FramedReader::new(reader, 4096);
+        let (sender, mut receiver) = unbounded_channel::<Vec<u8>>();
+        let idle_timeout = Duration::from_millis(self.settings.tcp_idle_timeout.unwrap_or(10000));
+
+        let write_task = tokio::spawn(async move {
+            while let Some(buf) = receiver.recv().await {
+                // tls keeps what the socket did not take yet until it is flushed
+                let written = async {
+                    writer.write_all(&get_wrapped_buf(&buf)).await?;
+                    writer.flush().await
+                };
+                if let Err(err) = written.await {
+                    println!("Failed to send back via tcp ({}), connection closed.", err);
+                    return;
+                }
+            }
+            writer.shutdown().await.ok();
+        });
+
+        loop {
+            let buf = match timeout(idle_timeout, reader.read_frame()).await {
+                Ok(Ok(buf)) => buf,
+                Ok(Err(FrameError::Closed)) => break,
+                Ok(Err(err)) => {
+                    println!(
+                        "Failed to read query from {} ({}), connection closed.",
+                        source, err
+                    );
+                    break;
+                }
+                // idle timeout
+                Err(_) => break,
+            };
+            self.spawn_task(get_target(sender.clone(), source.clone()), buf);
+        }
+
+        // the writer stops once all pending tasks have sent their replies
+        drop(sender);
+        write_task.await.ok();
+    }
+
     pub async fn start(&self) -> Result<(), Error> {
-        try_join(self.start_udp(), self.start_tcp()).await?;
+        try_join4(
+            self.start_udp(),
+            self.start_tcp(),
+            self.start_dot(),
+            self.start_doh(),
+        )
+        .await?;
         Ok(())
     }
 }
 
-async fn run_task(
-    server_udp: Arc<UdpSocket>,
-    _: Arc<TcpListener>,
-    settings: Arc<DNSSettings>,
-    custom_patterns: Arc<Vec<(Pattern, String)>>,
-    redis: Arc<Mutex<Option<Connection>>>,
-    geoip: Arc<GeoIP>,
-    target: TargetType,
-    buf: Vec<u8>,
-) -> Result<(), Error> {
-    let message = Message::from_octets(buf).unwrap();
-    let question = message.first_question().unwrap();
-    let domain = question.qname().to_string();
-    let identifier = format!(
-        "{}|{}|{}",
-        question.qname(),
-        question.qtype(),
-        question.qclass()
-    );
-
-    let is_china;
-    let mut is_cache = false;
+async fn run_task(server: DNSServer, target: TargetType, buf: Vec<u8>) -> Result<(), Error> {
+    // shorter than a header, there is even no id to reply with
+    let message = Message::from_octets(buf)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "Too short message, skip the task."))?;
+    if message.header().qr() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Not a query message, skip the task.",
+        ));
+    }
+
+    let client_ip = target
+        .source()
+        .parse::<SocketAddr>()
+        .ok()
+        .map(|addr| addr.ip());
+    let (domain, mut identifier) = match message.first_question() {
+        Some(question) => (
+            question.qname().to_string(),
+            format!(
+                "{}|{}|{}",
+                question.qname(),
+                question.qtype(),
+                question.qclass()
+            ),
+        ),
+        None => ("-".to_string(), String::new()),
+    };
+    // answers tailored to a client subnet are only shared within it
+    if let Some(scope) = server.ecs.get_cache_scope(&message, client_ip) {
+        identifier.push('|');
+        identifier.push_str(&scope);
+    }
+
+    // the upstream group which answered the query
+    let group;
+    // the answer is in the cache already, read from it or saved by a refresh
+    let mut is_saved = false;
     let response;
-    if let Ok(r) = lookup_custom(&message, &custom_patterns, &domain).await {
-        response = r;
-        is_china = true;
-    } else if let Ok((r, china)) = lookup_cache(&message, &redis, &identifier).await {
+    if let Err((rcode, error)) = check_request_message(&message) {
+        response = QueryResponse::Error(get_error_message(&message, rcode, error));
+        group = "-".to_string();
+    } else if let Ok(r) = lookup_custom(&message, &server.custom_patterns, &domain).await {
         response = r;
-        is_china = china;
-        is_cache = true;
+        group = "-".to_string();
     } else {
-        let message = get_request_message(&message);
-        if let Ok((r, is_china_)) = batch_query(&message, &settings.upstreams, geoip).await {
-            response = r;
-            is_china = is_china_;
-        } else {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("Timeout on batch query {}, skip the task.", domain),
-            ));
+        match lookup_cache(&message, &server.cache, &identifier).await {
+            Ok((r, cached_group, CacheState::Fresh { ttl, remaining })) => {
+                if server.prefetcher.hit(&identifier, ttl, remaining) {
+                    tokio::spawn(prefetch(
+                        server.clone(),
+                        message.clone(),
+                        client_ip,
+                        domain.clone(),
+                        identifier.clone(),
+                    ));
+                }
+                response = r;
+                group = cached_group;
+                is_saved = true;
+            }
+            Ok((stale, cached_group, CacheState::Stale)) => {
+                // upstreams are only waited for a while before the expired answer is served,
+                // the refresh goes on and saves its answer anyway. While one runs, queries
+                // for the same answer do not start another but are served the expired one.
+                let refreshed = if server.prefetcher.start(&identifier) {
+                    let refreshing = tokio::spawn(refresh(
+                        server.clone(),
+                        message.clone(),
+                        client_ip,
+                        domain.clone(),
+                        identifier.clone(),
+                    ));
+                    let stale_answer_timeout = server.settings.cache_stale_answer_timeout();
+                    match timeout(stale_answer_timeout, refreshing).await {
+                        Ok(result) => {
+                            result.unwrap_or_else(|e| Err(Error::new(ErrorKind::Other, e)))
+                        }
+                        Err(_) => Err(Error::new(ErrorKind::TimedOut, "still refreshing")),
+                    }
+                } else {
+                    Err(Error::new(ErrorKind::TimedOut, "still refreshing"))
+                };
+                match refreshed {
+                    Ok((r, answered_group)) => {
+                        response = r;
+                        group = answered_group;
+                    }
+                    Err(err) => {
+                        println!(
+                            "Failed on batch query {} ({}), answer with stale cache.",
+                            domain, err
+                        );
+                        response = stale;
+                        group = cached_group;
+                    }
+                }
+                is_saved = true;
+            }
+            Err(_) => match resolve(&server, &message, client_ip, &domain).await {
+                Ok((r, answered_group)) => {
+                    response = r;
+                    group = answered_group;
+                }
+                Err(err) => {
+                    println!(
+                        "Failed on batch query {} ({}), answer with SERVFAIL.",
+                        domain, err
+                    );
+                    let error = if err.kind() == ErrorKind::TimedOut {
+                        ExtendedError::new(
+                            ExtendedErrorCode::NoReachableAuthority,
+                            "upstreams timed out",
+                        )
+                    } else {
+                        ExtendedError::new(ExtendedErrorCode::NetworkError, "all upstreams failed")
+                    };
+                    response =
+                        QueryResponse::Error(get_error_message(&message, Rcode::ServFail, error));
+                    group = "-".to_string();
+                }
+            },
         }
     }
 
-    let (ret_message, method) = get_message_from_response(response);
+    let (mut ret_message, mut method) = get_message_from_response(response);
 
-    let answers = ret_message
-        .answer()
-        .unwrap()
-        .limit_to::<AllRecordData<_, _>>();
+    // an answer whose records do not parse is not passed on, the client would fail on it too
+    let answer_log = match get_answer_log(&ret_message) {
+        Some(answer_log) => answer_log,
+        None => {
+            println!(
+                "Unreadable answer of {} from {}, answer with SERVFAIL.",
+                domain, group
+            );
+            let error = ExtendedError::new(ExtendedErrorCode::InvalidData, "unreadable answer");
+            ret_message = get_error_message(&message, Rcode::ServFail, error);
+            method = QueryType::Error;
+            vec![]
+        }
+    };
 
-    let mut i: u8 = 0;
-    let mut answer_log = String::new();
-    for answer in answers {
-        i += 1;
-        let answer = answer.expect("parsing has failed.");
-        answer_log.push_str(format!("{} {}, ", answer.rtype(), answer.data().to_string()).as_str());
-    }
+    let mut ret_buf = ret_message.into_octets();
+    restore_response(&message, &mut ret_buf);
 
-    let ret_buf = ret_message.into_octets();
-
-    // save to cache
-    let mut redis = redis.lock().await;
-    if !is_cache && redis.is_some() && settings.cache_expire.is_some() {
-        let expire = settings.cache_expire.unwrap();
-        let redis = redis.as_mut().unwrap();
-        let mut cache_buf = ret_buf.clone();
-        cache_buf.push(is_china as u8);
-        match redis
-            .set_ex::<String, Vec<u8>, String>(identifier, cache_buf, expire)
-            .await
-        {
-            Ok(_) => {
-                //
-            }
-            Err(err) => {
-                println!("Failed to save data to cache ({}), ignored.", err);
-            }
-        };
+    // save to cache, error responses are never saved
+    let is_error = matches!(method, QueryType::Error);
+    if !is_saved && !is_error {
+        save_cache(
+            &server.cache,
+            &identifier,
+            &ret_buf,
+            &group,
+            &server.settings,
+        )
+        .await;
     }
 
     let t;
     let source;
     match target {
         TargetType::UDP(addr) => {
-            server_udp
+            // a client without EDNS accepts no more than 512 bytes,
+            // a too large reply is replaced by an empty one with TC set, so it retries over tcp
+            let ret_buf = if ret_buf.len() > get_udp_payload_size(&message) {
+                let ret_message = Message::from_octets(ret_buf).unwrap();
+                get_truncated_message(&message, &ret_message).into_octets()
+            } else {
+                ret_buf
+            };
+
+            server
+                .server_udp
                 .send_to(&ret_buf, addr.clone())
                 .await
                 .expect("failed to send back via udp.");
@@ -253,42 +570,148 @@ async fn run_task(
             t = "UDP";
             source = addr;
         }
-        TargetType::TCP(socket, addr) => {
-            socket.writable().await?;
-            socket
-                .try_write(&ret_buf)
-                .expect("failed to send back via tcp.");
+        TargetType::TCP(sender, addr) => {
+            sender
+                .send(ret_buf)
+                .map_err(|_| Error::new(ErrorKind::BrokenPipe, "tcp connection closed."))?;
 
             t = "TCP";
             source = addr
         }
+        TargetType::DoT(sender, addr) => {
+            sender
+                .send(ret_buf)
+                .map_err(|_| Error::new(ErrorKind::BrokenPipe, "tls connection closed."))?;
+
+            t = "DoT";
+            source = addr
+        }
+        TargetType::DoH(sender, addr) => {
+            sender
+                .send(ret_buf)
+                .map_err(|_| Error::new(ErrorKind::BrokenPipe, "https request canceled."))?;
+
+            t = "DoH";
+            source = addr
+        }
     }
 
-    if i == 0 {
+    if answer_log.is_empty() {
         println!(
-            "<{}> -> [{:?} {} {}] {} --> {} ({}) #{}",
-            t,
-            method,
-            if is_china { "China" } else { "Abroad" },
-            source,
-            domain,
-            "-",
-            "-",
-            i
+            "<{}> -> [{:?} {} {}] {} --> - (-) #0",
+            t, method, group, source, domain
         );
     } else {
-        answer_log.pop();
-        answer_log.pop();
         println!(
             "<{}> -> [{:?} {} {}] {} --> {}",
             t,
             method,
-            if is_china { "China" } else { "Abroad" },
+            group,
             source,
             domain,
-            answer_log
+            answer_log.join(", ")
         );
     }
 
     Ok(())
 }
+
+// `type data` of each answer record, none if any of them does not parse
+fn get_answer_log(message: &Message<Vec<u8>>) -> Option<Vec<String>> {
+    let answers = message.answer().ok()?.limit_to::<AllRecordData<_, _>>();
+    let mut answer_log = vec![];
+    for answer in answers {
+        let answer = answer.ok()?;
+        answer_log.push(format!("{} {}", answer.rtype(), answer.data()));
+    }
+    Some(answer_log)
+}
+
+// ask the upstream groups the route of the domain chooses
+async fn resolve(
+    server: &DNSServer,
+    message: &Message<Vec<u8>>,
+    client_ip: Option<IpAddr>,
+    domain: &str,
+) -> Result<(QueryResponse, String), Error> {
+    let udp_payload_size = server.settings.edns_udp_payload_size();
+    let get_request =
+        |origin: &Message<Vec<u8>>, upstream: &DNSServerUpstream, endpoint: &UpstreamEndpoint| {
+            let options = RequestOptions {
+                udp_payload_size,
+                client_subnet: server
+                    .ecs
+                    .get_client_subnet(origin, client_ip, upstream.group()),
+                padding: endpoint.is_encrypted(),
+                tcp_keepalive: endpoint.is_stream(),
+                randomize_case: upstream.case_randomization(),
+            };
+            get_request_message(origin, &options)
+        };
+    batch_query(
+        message,
+        get_request,
+        domain,
+        &server.settings,
+        server.geoip.clone(),
+        server.pool.clone(),
+        server.health.clone(),
+    )
+    .await
+}
+
+// resolve the query again and save the answer, which is returned as well,
+// once the refresh is claimed from the prefetcher
+async fn refresh(
+    server: DNSServer,
+    message: Message<Vec<u8>>,
+    client_ip: Option<IpAddr>,
+    domain: String,
+    identifier: String,
+) -> Result<(QueryResponse, String), Error> {
+    let resolved = resolve(&server, &message, client_ip, &domain).await;
+    if let Ok((response, group)) = &resolved {
+        let (ret_message, _) = get_message_from_response_ref(response);
+        let mut ret_buf = ret_message.as_octets().clone();
+        restore_response(&message, &mut ret_buf);
+        save_cache(
+            &server.cache,
+            &identifier,
+            &ret_buf,
+            group,
+            &server.settings,
+        )
+        .await;
+    }
+    server.prefetcher.finish(&identifier);
+    resolved
+}
+
+// refresh a cached answer in the background, before it expires
+async fn prefetch(
+    server: DNSServer,
+    message: Message<Vec<u8>>,
+    client_ip: Option<IpAddr>,
+    domain: String,
+    identifier: String,
+) {
+    let refreshed = refresh(
+        server,
+        message,
+        client_ip,
+        domain.clone(),
+        identifier.clone(),
+    )
+    .await;
+    match refreshed {
+        Ok((_, group)) => {
+            println!("[Prefetch] {} is refreshed by {}.", domain, group);
+        }
+        Err(err) => {
+            println!(
+                "[Prefetch] Failed to refresh {} ({}), ignored.",
+                domain, err
+            );
+        }
+    }
+}
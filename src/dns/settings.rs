@@ -1,37 +1,641 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, net::IpAddr};
 
+use super::domains::{parse_domain_list, DomainListFormat, DomainTrie};
+use super::lookup::utils::QueryType;
 use crate::dns::DNSServer;
+use crate::router::cidr::CidrSet;
+use glob::Pattern;
 use serde::{Deserialize, Serialize};
-use tokio::fs;
+use tokio::{fs, time::Duration};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DNSSettings {
     pub listen_ip: String,
     pub listen_port: u16,
     pub redis_server: Option<String>,
+    // the longest time an answer is cached, same as `cache.max_ttl`
     pub cache_expire: Option<usize>,
+    // where answers are cached, redis if only `redis_server` is given
+    pub cache: Option<DNSCacheSettings>,
     pub query_timeout: u32,
+    pub tcp_idle_timeout: Option<u64>,
+    // advertised to upstreams in EDNS, 1232 avoids ip fragmentation on most paths
+    pub edns_udp_payload_size: Option<u16>,
+    // edns client subnet sent to upstreams, stripped if absent
+    pub ecs: Option<DNSEcsSettings>,
     pub upstreams: Vec<DNSServerUpstream>,
+    // answers containing these networks are taken as forged, like dnsmasq `bogus-nxdomain`,
+    // and the next group of the chain is asked instead
+    pub bogus_ips: Option<Vec<String>>,
+    #[serde(skip)]
+    pub bogus_set: CidrSet,
+    // how upstreams of each group are queried, keyed by group name
+    pub groups: Option<HashMap<String, DNSUpstreamGroup>>,
+    // which groups answer a query, the first matching rule wins
+    pub rules: Option<Vec<DNSRoutingRule>>,
+    // lists of known domains, each answered by its group alone, checked after rules
+    pub domain_lists: Option<Vec<DNSDomainList>>,
+    // groups tried in order for queries no rule or list matches, `["china", "abroad"]` by default
+    pub default_groups: Option<Vec<String>>,
+    // domains of all lists, to the index of their list
+    #[serde(skip)]
+    pub domain_trie: DomainTrie,
     pub custom_hosts: HashMap<String, String>,
+    pub dot: Option<DNSTlsListener>,
+    pub doh: Option<DNSDoHListener>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DNSTlsListener {
+    pub listen_port: u16,
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheBackend {
+    // in this process, lost on restart
+    Memory,
+    // the server at `redis_server`, shared by instances
+    Redis,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheEviction {
+    // the least recently used entry goes first
+    Lru,
+    // the least frequently used entry goes first
+    Lfu,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DNSCacheSettings {
+    pub backend: Option<CacheBackend>,
+    // bounds of the memory backend, split evenly among shards
+    pub max_entries: Option<usize>,
+    pub max_bytes: Option<usize>,
+    pub shards: Option<usize>,
+    pub eviction: Option<CacheEviction>,
+    // answers are cached as long as their lowest ttl, within these bounds in seconds
+    pub min_ttl: Option<u32>,
+    pub max_ttl: Option<u32>,
+    // NXDOMAIN and NODATA answers are cached as long as their SOA says (RFC 2308),
+    // at most this many seconds, 3600 by default, 0 disables them
+    pub negative_max_ttl: Option<u32>,
+    // seconds expired answers are kept, to be served when upstreams fail (RFC 8767)
+    pub serve_stale: Option<u32>,
+    // milliseconds upstreams are waited for before an expired answer is served,
+    // 1800 by default as RFC 8767 suggests, the refresh goes on in the background
+    pub stale_answer_timeout: Option<u64>,
+    // refresh answers in the background when they are queried close to their expiry,
+    // once they have been queried `prefetch_hits` times, 3 by default
+    pub prefetch: Option<bool>,
+    pub prefetch_hits: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamStrategy {
+    // every endpoint at once, the first valid answer wins
+    Race,
+    // the best endpoint first, one more each time `hedge_delay` passes without an answer
+    Hedged,
+    // only the endpoint with the lowest latency, the next one if it fails
+    Fastest,
+    // endpoints take turns, the next one is tried if one fails
+    RoundRobin,
+    // endpoints in the configured order, the next one is tried if one fails
+    Fallback,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupAccept {
+    // any valid answer
+    Any,
+    // only answers pointing to home countries or networks, see `data/geoip_settings.json`
+    Geoip,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DNSUpstreamGroup {
+    pub strategy: Option<UpstreamStrategy>,
+    pub hedge_delay: Option<u64>,
+    // budget of the group within `query_timeout`, the rest is left to the next groups
+    pub query_timeout: Option<u64>,
+    // answers not accepted move on to the next group of the chain,
+    // the last group is always accepted
+    pub accept: Option<GroupAccept>,
+    // with `geoip`, classify AAAA queries by an A query for the same name sent alongside
+    pub companion_a: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DNSDomainList {
+    pub file: String,
+    pub format: DomainListFormat,
+    pub group: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DNSRoutingRule {
+    // glob patterns of domain names, e.g. `*.corp.example.com`
+    pub domains: Vec<String>,
+    // groups tried in order
+    pub groups: Vec<String>,
+    #[serde(skip)]
+    pub patterns: Vec<Pattern>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EcsPolicy {
+    // never send a client subnet
+    Strip,
+    // the subnet sent by the client, or the one of its public source address
+    Forward,
+    // the subnet configured for the upstream group
+    Fixed,
+    // the subnet of our own public address
+    Auto,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DNSEcsSettings {
+    pub policy: EcsPolicy,
+    // how much of an address is revealed by `forward` and `auto`, 24 and 56 by default
+    pub ipv4_prefix: Option<u8>,
+    pub ipv6_prefix: Option<u8>,
+    // subnets for `fixed`, by upstream group, e.g. `{"china": "1.2.3.0/24"}`
+    pub subnets: Option<HashMap<String, String>>,
+    // queried for our public address by `auto`, ns1.google.com by default
+    pub detect_server: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DNSServerUpstream {
+    #[serde(default)]
     pub address: String,
+    #[serde(default)]
     pub hostname: String,
+    #[serde(default)]
     pub enable_udp: bool,
+    #[serde(default)]
     pub enable_tcp: bool,
+    #[serde(default)]
     pub enable_doh: bool,
+    #[serde(default)]
     pub enable_dot: bool,
+    // shorthand for the `china` and `abroad` groups, when `group` is absent
+    #[serde(default)]
     pub is_china: bool,
+    pub group: Option<String>,
+    pub connect_timeout: Option<u64>,
+    pub read_timeout: Option<u64>,
+    // how long an unused tcp or tls connection is kept, unless the upstream asks otherwise
+    pub idle_timeout: Option<u64>,
+    // max number of pipelined tcp or tls connections to each endpoint
+    pub pool_size: Option<usize>,
+    // milliseconds to keep listening after a suspicious udp answer, a later answer which
+    // passes the checks means the first one was injected on the path and replaces it
+    pub injection_wait: Option<u64>,
+    // milliseconds within which no real answer can come back, injectors sitting on the path
+    // answer faster than the upstream itself
+    pub injection_min_rtt: Option<u64>,
+    // only take udp answers with the DO bit set, which injected answers usually lack,
+    // for upstreams known to support EDNS
+    pub udp_require_dnssec_ok: Option<bool>,
+    // randomize the case of query names (0x20 encoding), for upstreams that echo it
+    pub case_randomization: Option<bool>,
+    // `GET` or `POST` (default) for DoH requests
+    pub doh_method: Option<String>,
+    // e.g. `udp://1.1.1.1:5353`, `tls://dns.google:853`, `https://dns.google/dns-query`
+    pub urls: Option<Vec<String>>,
+    #[serde(skip)]
+    pub endpoints: Vec<UpstreamEndpoint>,
+}
+
+#[derive(Debug, Clone)]
+pub struct UpstreamEndpoint {
+    pub protocol: QueryType,
+    // ip address to connect to
+    pub address: String,
+    pub port: u16,
+    // server name for tls and the http host header
+    pub hostname: String,
+    // request path, only for DoH
+    pub path: String,
+}
+
+impl DNSSettings {
+    pub fn edns_udp_payload_size(&self) -> u16 {
+        self.edns_udp_payload_size.unwrap_or(1232).max(512)
+    }
+
+    pub fn query_timeout(&self) -> Duration {
+        Duration::from_millis(self.query_timeout as u64)
+    }
+
+    // none if answers are not cached
+    pub fn cache_backend(&self) -> Option<CacheBackend> {
+        let backend = self.cache.as_ref().and_then(|cache| cache.backend);
+        match (backend, &self.cache, &self.redis_server) {
+            (Some(backend), _, _) => Some(backend),
+            (None, _, Some(_)) => Some(CacheBackend::Redis),
+            (None, Some(_), None) => Some(CacheBackend::Memory),
+            (None, None, None) => None,
+        }
+    }
+
+    pub fn cache_min_ttl(&self) -> u32 {
+        self.cache
+            .as_ref()
+            .and_then(|cache| cache.min_ttl)
+            .unwrap_or(0)
+    }
+
+    pub fn cache_max_ttl(&self) -> u32 {
+        self.cache
+            .as_ref()
+            .and_then(|cache| cache.max_ttl)
+            .or_else(|| self.cache_expire.map(|expire| expire as u32))
+            .unwrap_or(86400)
+            .max(self.cache_min_ttl())
+    }
+
+    pub fn cache_negative_max_ttl(&self) -> u32 {
+        self.cache
+            .as_ref()
+            .and_then(|cache| cache.negative_max_ttl)
+            .unwrap_or(3600)
+    }
+
+    pub fn cache_serve_stale(&self) -> u32 {
+        self.cache
+            .as_ref()
+            .and_then(|cache| cache.serve_stale)
+            .unwrap_or(0)
+    }
+
+    pub fn cache_stale_answer_timeout(&self) -> Duration {
+        let stale_answer_timeout = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.stale_answer_timeout)
+            .unwrap_or(1800);
+        Duration::from_millis(stale_answer_timeout)
+    }
+
+    // none if answers are not prefetched, the hits an answer needs otherwise
+    pub fn cache_prefetch_hits(&self) -> Option<u32> {
+        let cache = self.cache.as_ref()?;
+        if cache.prefetch.unwrap_or(false) {
+            Some(cache.prefetch_hits.unwrap_or(3).max(1))
+        } else {
+            None
+        }
+    }
+
+    pub fn get_group(&self, name: &str) -> DNSUpstreamGroup {
+        let mut group = self
+            .groups
+            .as_ref()
+            .and_then(|groups| groups.get(name))
+            .cloned()
+            .unwrap_or_default();
+        // the china group only answers sites at home, unless configured otherwise
+        if name == "china" && group.accept.is_none() {
+            group.accept = Some(GroupAccept::Geoip);
+        }
+        group
+    }
+
+    // the chain of groups to answer a domain
+    pub fn get_route(&self, domain: &str) -> Vec<String> {
+        if let Some(rules) = &self.rules {
+            for rule in rules {
+                if rule.patterns.iter().any(|pattern| pattern.matches(domain)) {
+                    return rule.groups.clone();
+                }
+            }
+        }
+        if let Some(lists) = &self.domain_lists {
+            if let Some(i) = self.domain_trie.lookup(domain) {
+                return vec![lists[i].group.clone()];
+            }
+        }
+        match &self.default_groups {
+            Some(groups) => groups.clone(),
+            None => vec!["china".to_string(), "abroad".to_string()],
+        }
+    }
+}
+
+impl DNSCacheSettings {
+    pub fn max_entries(&self) -> usize {
+        self.max_entries.unwrap_or(10000)
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes.unwrap_or(16 * 1024 * 1024)
+    }
+
+    pub fn shards(&self) -> usize {
+        self.shards.unwrap_or(16).max(1)
+    }
+
+    pub fn eviction(&self) -> CacheEviction {
+        self.eviction.unwrap_or(CacheEviction::Lru)
+    }
+}
+
+impl DNSUpstreamGroup {
+    pub fn strategy(&self) -> UpstreamStrategy {
+        self.strategy.unwrap_or(UpstreamStrategy::Race)
+    }
+
+    pub fn hedge_delay(&self) -> Duration {
+        Duration::from_millis(self.hedge_delay.unwrap_or(100))
+    }
+
+    pub fn query_timeout(&self) -> Option<Duration> {
+        self.query_timeout.map(Duration::from_millis)
+    }
+
+    pub fn accept(&self) -> GroupAccept {
+        self.accept.unwrap_or(GroupAccept::Any)
+    }
+
+    pub fn companion_a(&self) -> bool {
+        self.companion_a.unwrap_or(false)
+    }
+}
+
+impl DNSServerUpstream {
+    // timeout for establishing a connection, including tls handshake
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.connect_timeout.unwrap_or(2000))
+    }
+
+    // timeout for waiting the response after the query is sent
+    pub fn read_timeout(&self) -> Duration {
+        Duration::from_millis(self.read_timeout.unwrap_or(2000))
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_millis(self.idle_timeout.unwrap_or(10000))
+    }
+
+    pub fn pool_size(&self) -> usize {
+        self.pool_size.unwrap_or(2).max(1)
+    }
+
+    // name of the group the upstream belongs to
+    pub fn group(&self) -> &str {
+        match &self.group {
+            Some(group) => group.as_str(),
+            None if self.is_china => "china",
+            None => "abroad",
+        }
+    }
+
+    pub fn injection_wait(&self) -> Option<Duration> {
+        self.injection_wait.map(Duration::from_millis)
+    }
+
+    pub fn injection_min_rtt(&self) -> Duration {
+        Duration::from_millis(self.injection_min_rtt.unwrap_or(0))
+    }
+
+    pub fn udp_require_dnssec_ok(&self) -> bool {
+        self.udp_require_dnssec_ok.unwrap_or(false)
+    }
+
+    pub fn case_randomization(&self) -> bool {
+        self.case_randomization.unwrap_or(false)
+    }
+
+    pub fn is_doh_get(&self) -> bool {
+        match &self.doh_method {
+            Some(method) => method.eq_ignore_ascii_case("GET"),
+            None => false,
+        }
+    }
+
+    // every enabled protocol of the upstream, from both the `enable_*` shorthands and urls
+    pub fn get_endpoints(&self) -> Result<Vec<UpstreamEndpoint>, String> {
+        let mut endpoints = vec![];
+
+        let shorthands = [
+            (self.enable_udp, QueryType::UDP, 53),
+            (self.enable_tcp, QueryType::TCP, 53),
+            (self.enable_dot, QueryType::DoT, 853),
+            (self.enable_doh, QueryType::DoH, 443),
+        ];
+        for (enabled, protocol, port) in shorthands.iter() {
+            if *enabled {
+                endpoints.push(self.get_endpoint(
+                    *protocol,
+                    self.address.as_str(),
+                    *port,
+                    "/dns-query",
+                )?);
+            }
+        }
+
+        if let Some(urls) = &self.urls {
+            for url in urls {
+                endpoints.push(self.parse_url(url)?);
+            }
+        }
+
+        Ok(endpoints)
+    }
+
+    fn parse_url(&self, url: &str) -> Result<UpstreamEndpoint, String> {
+        let separator = url
+            .find("://")
+            .ok_or_else(|| format!("missing scheme in upstream url {}", url))?;
+        let (protocol, default_port) = match &url[..separator] {
+            "udp" => (QueryType::UDP, 53),
+            "tcp" => (QueryType::TCP, 53),
+            "tls" => (QueryType::DoT, 853),
+            "https" => (QueryType::DoH, 443),
+            scheme => return Err(format!("unsupported scheme {} in {}", scheme, url)),
+        };
+
+        let rest = &url[separator + 3..];
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/dns-query"),
+        };
+
+        // `[::1]:53`, `[::1]`, `::1`, `1.1.1.1:53` or `dns.google`
+        let (host, port) = if authority.starts_with('[') {
+            let end = authority
+                .find(']')
+                .ok_or_else(|| format!("invalid ipv6 address in {}", url))?;
+            let port = match authority[end + 1..].strip_prefix(':') {
+                Some(port) => Some(port),
+                None if authority.len() == end + 1 => None,
+                None => return Err(format!("invalid port in {}", url)),
+            };
+            (&authority[1..end], port)
+        } else if authority.matches(':').count() == 1 {
+            let i = authority.find(':').unwrap();
+            (&authority[..i], Some(&authority[i + 1..]))
+        } else {
+            (authority, None)
+        };
+        let port = match port {
+            Some(port) => port
+                .parse::<u16>()
+                .map_err(|_| format!("invalid port in {}", url))?,
+            None => default_port,
+        };
+
+        if host.is_empty() {
+            return Err(format!("missing host in {}", url));
+        }
+
+        self.get_endpoint(protocol, host, port, path)
+    }
+
+    fn get_endpoint(
+        &self,
+        protocol: QueryType,
+        host: &str,
+        port: u16,
+        path: &str,
+    ) -> Result<UpstreamEndpoint, String> {
+        // a host name is used as server name and we still connect to the configured address,
+        // so that no other resolver is needed to reach upstreams
+        let (address, hostname) = if host.parse::<IpAddr>().is_ok() {
+            (host.to_string(), self.hostname.clone())
+        } else {
+            (self.address.clone(), host.to_string())
+        };
+
+        if address.parse::<IpAddr>().is_err() {
+            return Err(format!(
+                "upstream {} requires an ip address to connect to",
+                host
+            ));
+        }
+        if hostname.is_empty() && (protocol == QueryType::DoT || protocol == QueryType::DoH) {
+            return Err(format!("upstream {} requires a hostname for tls", host));
+        }
+
+        Ok(UpstreamEndpoint {
+            protocol,
+            address,
+            port,
+            hostname,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl UpstreamEndpoint {
+    // `ip:port` form that also works for ipv6
+    pub fn socket_addr(&self) -> String {
+        match self.address.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, self.port),
+            _ => format!("{}:{}", self.address, self.port),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.protocol == QueryType::DoT || self.protocol == QueryType::DoH
+    }
+
+    pub fn is_stream(&self) -> bool {
+        self.protocol == QueryType::TCP || self.protocol == QueryType::DoT
+    }
+}
+
+impl fmt::Display for UpstreamEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.protocol {
+            QueryType::DoH => write!(
+                f,
+                "https://{}{}@{}",
+                self.hostname,
+                self.path,
+                self.socket_addr()
+            ),
+            QueryType::DoT => write!(f, "tls://{}@{}", self.hostname, self.socket_addr()),
+            QueryType::TCP => write!(f, "tcp://{}", self.socket_addr()),
+            _ => write!(f, "udp://{}", self.socket_addr()),
+        }
+    }
 }
 
 impl DNSServer {
     pub async fn load_settings() -> DNSSettings {
         match fs::read_to_string("data/dns_settings.json").await {
             Ok(text) => {
-                let settings: DNSSettings =
+                let mut settings: DNSSettings =
                     serde_json::from_str(text.as_str()).expect("Failed to load settings.");
+                for upstream in settings.upstreams.iter_mut() {
+                    upstream.endpoints = match upstream.get_endpoints() {
+                        Ok(endpoints) => endpoints,
+                        Err(e) => panic!("failed to load upstream: {}", e),
+                    };
+                }
+                // the answering group is saved along with cached answers, its length in a byte
+                let groups = settings.groups.iter().flat_map(|groups| groups.keys());
+                let upstream_groups = settings.upstreams.iter().map(|upstream| upstream.group());
+                for group in groups.map(|group| group.as_str()).chain(upstream_groups) {
+                    if group.len() > u8::MAX as usize {
+                        panic!("group name {} is longer than 255 bytes", group);
+                    }
+                }
+                if let Some(bogus_ips) = &settings.bogus_ips {
+                    for cidr in bogus_ips {
+                        if let Err(e) = settings.bogus_set.insert(cidr) {
+                            panic!("failed to load bogus ips: {}", e);
+                        }
+                    }
+                    settings.bogus_set.build();
+                }
+                if let Some(lists) = &settings.domain_lists {
+                    for (i, list) in lists.iter().enumerate() {
+                        let text = match fs::read_to_string(&list.file).await {
+                            Ok(text) => text,
+                            Err(e) => {
+                                println!("[Domains] Failed to load list {}: {}", list.file, e);
+                                continue;
+                            }
+                        };
+                        let domains = parse_domain_list(&text, list.format);
+                        println!(
+                            "[Domains] Loaded {} domains for group {} from {}",
+                            domains.len(),
+                            list.group,
+                            list.file
+                        );
+                        for domain in domains {
+                            settings.domain_trie.insert(&domain, i);
+                        }
+                    }
+                    println!(
+                        "[Domains] Loaded {} distinct domains in all",
+                        settings.domain_trie.len()
+                    );
+                }
+                if let Some(rules) = settings.rules.as_mut() {
+                    for rule in rules.iter_mut() {
+                        for domain in &rule.domains {
+                            match Pattern::new(domain.as_str()) {
+                                Ok(pattern) => rule.patterns.push(pattern),
+                                Err(_) => panic!("invalid domain pattern {} in rules", domain),
+                            }
+                        }
+                    }
+                }
                 println!("dns settings:\n{:?}", settings);
                 settings
             }
@@ -41,3 +645,10 @@ impl DNSServer {
         }
     }
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DNSDoHListener {
+    #[serde(flatten)]
+    pub listener: DNSTlsListener,
+    pub path: Option<String>,
+}
@@ -0,0 +1,52 @@
+use std::io::{Error, ErrorKind};
+use tokio::fs;
+use tokio_rustls::rustls::{
+    internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+    NoClientAuth, ServerConfig,
+};
+
+// build a tls server config from pem encoded certificate chain and private key files
+pub async fn load_server_config(
+    cert_file: &String,
+    key_file: &String,
+    alpn_protocols: Vec<Vec<u8>>,
+) -> Result<ServerConfig, Error> {
+    let cert_buf = fs::read(cert_file).await?;
+    let key_buf = fs::read(key_file).await?;
+
+    let cert_chain = certs(&mut cert_buf.as_slice()).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("[TLS] Failed to parse certificates in {}", cert_file),
+        )
+    })?;
+    if cert_chain.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("[TLS] No certificate found in {}", cert_file),
+        ));
+    }
+
+    // try PKCS#8 first, then fall back to PKCS#1 (RSA) keys
+    let mut keys = pkcs8_private_keys(&mut key_buf.as_slice()).unwrap_or_default();
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut key_buf.as_slice()).unwrap_or_default();
+    }
+    let key = match keys.into_iter().next() {
+        Some(key) => key,
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("[TLS] No private key found in {}", key_file),
+            ));
+        }
+    };
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(cert_chain, key)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("[TLS] {}", e)))?;
+    config.set_protocols(&alpn_protocols);
+
+    Ok(config)
+}
@@ -1,25 +1,104 @@
+use super::ede::{ExtendedError, ExtendedErrorCode};
 use domain::{
     base::Message,
     base::{
-        iana::{Opcode, Rcode},
+        iana::{Opcode, OptionCode, Rcode, Rtype},
+        octets::{Compose, OctetsBuilder, ShortBuf},
         opt::rfc7830::PaddingMode,
-        MessageBuilder,
+        opt::OptData,
+        Dname, MessageBuilder,
     },
 };
 use domain::{
     base::{
-        opt::Opt,
-        opt::{ClientSubnet, KeyTag, Padding, TcpKeepalive},
+        opt::{ClientSubnet, Opt, Padding},
         record::AsRecord,
     },
     rdata::AllRecordData,
 };
+use rand::Rng;
+use std::net::IpAddr;
 
-pub fn get_request_message(origin: &Message<Vec<u8>>) -> Message<Vec<u8>> {
+// queries are padded to a multiple of this size (RFC 8467 4.1)
+const PADDING_BLOCK_SIZE: usize = 128;
+
+// what goes into the OPT record of a query sent to an upstream
+pub struct RequestOptions {
+    pub udp_payload_size: u16,
+    pub client_subnet: Option<(IpAddr, u8)>,
+    // padding only makes sense on encrypted transports
+    pub padding: bool,
+    // edns-tcp-keepalive must not be sent over udp (RFC 7828 3.2.1)
+    pub tcp_keepalive: bool,
+    // mix the case of the name (0x20 encoding), the answer has to echo it exactly
+    pub randomize_case: bool,
+}
+
+// edns-tcp-keepalive without a timeout, the only form a client may send
+struct TcpKeepaliveRequest;
+
+impl Compose for TcpKeepaliveRequest {
+    fn compose<T: OctetsBuilder>(&self, _: &mut T) -> Result<(), ShortBuf> {
+        Ok(())
+    }
+}
+
+impl OptData for TcpKeepaliveRequest {
+    fn code(&self) -> OptionCode {
+        OptionCode::from_int(11)
+    }
+}
+
+// The query sent to an upstream for a client's one. It never reuses the client's id,
+// each query gets a random one so that answers are hard to forge.
+pub fn get_request_message(
+    origin: &Message<Vec<u8>>,
+    options: &RequestOptions,
+) -> Message<Vec<u8>> {
+    let id = rand::random::<u16>();
+    let qname = match origin.first_question() {
+        Some(question) if options.randomize_case => {
+            let name = get_randomized_case(&question.qname().to_string());
+            Dname::vec_from_str(&name).ok()
+        }
+        _ => None,
+    };
+
+    let msg = build_request_message(origin, options, id, &qname, None);
+    if !options.padding {
+        return msg;
+    }
+
+    // the padding option itself takes 4 bytes
+    let size = msg.as_octets().len() + 4;
+    let padding = (PADDING_BLOCK_SIZE - size % PADDING_BLOCK_SIZE) % PADDING_BLOCK_SIZE;
+    build_request_message(origin, options, id, &qname, Some(padding as u16))
+}
+
+fn get_randomized_case(name: &str) -> String {
+    let mut rng = rand::thread_rng();
+    name.chars()
+        .map(|c| {
+            if rng.gen::<bool>() {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
+
+fn build_request_message(
+    origin: &Message<Vec<u8>>,
+    options: &RequestOptions,
+    id: u16,
+    qname: &Option<Dname<Vec<u8>>>,
+    padding: Option<u16>,
+) -> Message<Vec<u8>> {
     let mut msg = MessageBuilder::new_vec();
     let header_mut = msg.header_mut();
     header_mut.set_opcode(Opcode::Query);
-    header_mut.set_id(origin.header().id());
+    header_mut.set_id(id);
     header_mut.set_rd(true);
     header_mut.set_aa(true);
     header_mut.set_ra(true);
@@ -30,39 +109,129 @@ pub fn get_request_message(origin: &Message<Vec<u8>>) -> Message<Vec<u8>> {
 
     for question in origin.question() {
         let question = question.unwrap();
-        msg.push(question).unwrap();
+        match qname {
+            Some(qname) => msg
+                .push((qname.clone(), question.qtype(), question.qclass()))
+                .unwrap(),
+            None => msg.push(question).unwrap(),
+        }
     }
     let msg = msg.answer();
 
+    // options of the client are not forwarded, they are meant for us rather than upstreams
+    let dnssec_ok = origin.opt().map(|opt| opt.dnssec_ok()).unwrap_or(true);
     let mut msg = msg.additional();
-    let mut additionals_copied = false;
-    let options = origin.additional().unwrap();
-    for record in options {
-        let option = record
-            .unwrap()
-            .into_record::<Opt<&[u8]>>()
-            .unwrap()
-            .unwrap();
-        msg.push(&option).unwrap();
-        additionals_copied = true;
+    msg.opt(|opt| {
+        opt.set_dnssec_ok(dnssec_ok);
+        opt.set_udp_payload_size(options.udp_payload_size);
+        opt.set_version(0);
+        if let Some((addr, prefix)) = options.client_subnet {
+            opt.push(&ClientSubnet::new(prefix, 0, addr))?;
+        }
+        if options.tcp_keepalive {
+            opt.push(&TcpKeepaliveRequest)?;
+        }
+        // padding goes last so that it covers everything before it
+        if let Some(padding) = padding {
+            opt.push(&Padding::new(padding, PaddingMode::Zero))?;
+        }
+        Ok(())
+    })
+    .unwrap();
+
+    let buf = msg.finish();
+    Message::from_octets(buf).unwrap()
+}
+
+// check whether a request is something we are able to answer,
+// or returns the rcode and the reason to reject it with
+pub fn check_request_message(message: &Message<Vec<u8>>) -> Result<(), (Rcode, ExtendedError)> {
+    let header = message.header();
+    if header.opcode() != Opcode::Query {
+        return Err((
+            Rcode::NotImp,
+            ExtendedError::new(
+                ExtendedErrorCode::NotSupported,
+                format!("opcode {} is not supported", header.opcode()).as_str(),
+            ),
+        ));
+    }
+    if message.header_counts().qdcount() != 1 || message.first_question().is_none() {
+        return Err((
+            Rcode::FormErr,
+            ExtendedError::new(
+                ExtendedErrorCode::Other,
+                "query must contain exactly one question",
+            ),
+        ));
     }
+    if message.additional().is_err() {
+        return Err((
+            Rcode::FormErr,
+            ExtendedError::new(ExtendedErrorCode::Other, "malformed query"),
+        ));
+    }
+    Ok(())
+}
 
-    if !additionals_copied {
+// build an empty response carrying the given rcode, with an extended error if client supports EDNS
+pub fn get_error_message(
+    origin: &Message<Vec<u8>>,
+    rcode: Rcode,
+    error: ExtendedError,
+) -> Message<Vec<u8>> {
+    let mut msg = MessageBuilder::new_vec();
+    let header = origin.header();
+    let header_mut = msg.header_mut();
+    header_mut.set_id(header.id());
+    header_mut.set_opcode(header.opcode());
+    header_mut.set_rd(header.rd());
+    header_mut.set_cd(header.cd());
+    header_mut.set_ra(true);
+    header_mut.set_qr(true);
+    header_mut.set_rcode(rcode);
+
+    let mut msg = msg.question();
+    // a malformed question is not echoed back
+    if rcode != Rcode::FormErr {
+        if let Some(question) = origin.first_question() {
+            msg.push(question).unwrap();
+        }
+    }
+
+    let mut msg = msg.additional();
+    if origin.opt().is_some() {
+        msg.opt(|opt| {
+            opt.set_udp_payload_size(1232);
+            opt.push(&error)
+        })
+        .unwrap();
+    }
+
+    let buf = msg.finish();
+    Message::from_octets(buf).unwrap()
+}
+
+// an empty response with TC set, for answers that do not fit in the client's udp buffer
+pub fn get_truncated_message(
+    origin: &Message<Vec<u8>>,
+    response: &Message<Vec<u8>>,
+) -> Message<Vec<u8>> {
+    let mut msg = MessageBuilder::new_vec();
+    *msg.header_mut() = response.header();
+    msg.header_mut().set_tc(true);
+
+    let mut msg = msg.question();
+    if let Some(question) = origin.first_question() {
+        msg.push(question).unwrap();
+    }
+
+    let mut msg = msg.additional();
+    if let Some(opt) = origin.opt() {
+        let dnssec_ok = opt.dnssec_ok();
         msg.opt(|opt| {
-            opt.set_dnssec_ok(true);
-            opt.set_udp_payload_size(1024);
-            opt.set_version(0);
-            let option1 = ClientSubnet::new(24, 0, "122.233.242.188".parse().unwrap());
-            let option2 = ClientSubnet::new(64, 0, "240e:390:e5b:8280::1".parse().unwrap());
-            let padding = Padding::new(31, PaddingMode::Zero);
-            let tcp_keepalive = TcpKeepalive::new(20);
-            let key_tag = KeyTag::new(&[1, 2, 3, 82]);
-
-            opt.push(&option1).unwrap();
-            opt.push(&option2).unwrap();
-            opt.push(&padding).unwrap();
-            opt.push(&tcp_keepalive).unwrap();
-            opt.push(&key_tag).unwrap();
+            opt.set_udp_payload_size(1232);
+            opt.set_dnssec_ok(dnssec_ok);
             Ok(())
         })
         .unwrap();
@@ -72,6 +241,225 @@ pub fn get_request_message(origin: &Message<Vec<u8>>) -> Message<Vec<u8>> {
     Message::from_octets(buf).unwrap()
 }
 
+// A saved answer rebuilt for the client asking now, with its rcode and the SOA of negative
+// answers. Expired answers served because upstreams failed (RFC 8767) carry an extended error.
+pub fn get_cached_message(
+    origin: &Message<Vec<u8>>,
+    saved: &Message<Vec<u8>>,
+    error: Option<ExtendedError>,
+) -> Message<Vec<u8>> {
+    let mut msg = MessageBuilder::new_vec()
+        .start_answer(origin, saved.header().rcode())
+        .unwrap();
+    let header = saved.header();
+    let header_mut = msg.header_mut();
+    header_mut.set_aa(header.aa());
+    header_mut.set_ra(header.ra());
+    header_mut.set_ad(header.ad());
+
+    let answers = saved.answer().unwrap().limit_to::<AllRecordData<_, _>>();
+    for answer in answers {
+        let answer = answer.expect("parsing has failed.");
+        msg.push(answer).unwrap();
+    }
+
+    // the SOA of negative answers
+    let mut msg = msg.authority();
+    let records = saved.authority().unwrap().limit_to::<AllRecordData<_, _>>();
+    for record in records {
+        let record = record.expect("parsing has failed.");
+        msg.push(record).unwrap();
+    }
+
+    let mut msg = msg.additional();
+    if origin.opt().is_some() {
+        let dnssec_ok = saved.opt().map(|opt| opt.dnssec_ok()).unwrap_or(false);
+        msg.opt(|opt| {
+            opt.set_udp_payload_size(1232);
+            opt.set_dnssec_ok(dnssec_ok);
+            match &error {
+                Some(error) => opt.push(error),
+                None => Ok(()),
+            }
+        })
+        .unwrap();
+    }
+
+    let buf = msg.finish();
+    Message::from_octets(buf).unwrap()
+}
+
+// give the client back its own id and name case, which were changed for upstreams
+pub fn restore_response(origin: &Message<Vec<u8>>, buf: &mut [u8]) {
+    if buf.len() < 12 {
+        return;
+    }
+    buf[..2].copy_from_slice(&origin.header().id().to_be_bytes());
+
+    // the question name follows the header in both messages, with the same length,
+    // and the names in answers mostly point to it
+    let origin_buf = origin.as_slice();
+    let name_len = match get_name_len(origin_buf, 12) {
+        Some(len) => len,
+        None => return,
+    };
+    let name = 12..12 + name_len;
+    let has_question = buf[4] != 0 || buf[5] != 0;
+    if has_question
+        && buf.len() >= name.end
+        && buf[name.clone()].eq_ignore_ascii_case(&origin_buf[name.clone()])
+    {
+        buf[name.clone()].copy_from_slice(&origin_buf[name]);
+    }
+}
+
+// length of an uncompressed name in wire format starting at `offset`
+fn get_name_len(buf: &[u8], offset: usize) -> Option<usize> {
+    let mut pos = offset;
+    loop {
+        let len = *buf.get(pos)? as usize;
+        if len == 0 {
+            return Some(pos + 1 - offset);
+        }
+        // compression pointers are not expected in a question
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        pos += 1 + len;
+    }
+}
+
+// position of a name in wire format after `offset`, following no compression pointer
+fn skip_name(buf: &[u8], offset: usize) -> Option<usize> {
+    let mut pos = offset;
+    loop {
+        let len = *buf.get(pos)? as usize;
+        if len == 0 {
+            return Some(pos + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            return Some(pos + 2);
+        }
+        pos += 1 + len;
+    }
+}
+
+// where the ttl of each record is, with its type and section: answer (0), authority (1)
+// or additional (2), OPT records are left out since their ttl field holds flags
+fn get_ttl_offsets(buf: &[u8]) -> Option<Vec<(usize, usize, usize)>> {
+    let count = |i: usize| Some(u16::from_be_bytes([*buf.get(i)?, *buf.get(i + 1)?]) as usize);
+    let qdcount = count(4)?;
+    let sections = [count(6)?, count(8)?, count(10)?];
+
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        pos = skip_name(buf, pos)? + 4;
+    }
+    let mut offsets = vec![];
+    for (section, &records) in sections.iter().enumerate() {
+        for _ in 0..records {
+            pos = skip_name(buf, pos)?;
+            let rtype = count(pos)?;
+            let rdlen = count(pos + 8)?;
+            if rtype != Rtype::Opt.to_int() as usize {
+                offsets.push((section, rtype, pos + 4));
+            }
+            pos += 10 + rdlen;
+        }
+    }
+    if pos > buf.len() {
+        return None;
+    }
+    Some(offsets)
+}
+
+fn get_ttl(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+// the lowest ttl among the answers, none without answers
+pub fn get_min_answer_ttl(buf: &[u8]) -> Option<u32> {
+    get_ttl_offsets(buf)?
+        .into_iter()
+        .filter(|(section, _, _)| *section == 0)
+        .map(|(_, _, offset)| get_ttl(buf, offset))
+        .min()
+}
+
+// how long a NXDOMAIN or NODATA reply may be cached (RFC 2308 5), that is the lower of
+// the ttl and the minimum field of its SOA, none for other replies or without SOA
+pub fn get_negative_ttl(buf: &[u8]) -> Option<u32> {
+    let rcode = *buf.get(3)? & 0x0f;
+    let ancount = u16::from_be_bytes([*buf.get(6)?, *buf.get(7)?]);
+    let is_negative =
+        rcode == Rcode::NXDomain.to_int() || (rcode == Rcode::NoError.to_int() && ancount == 0);
+    if !is_negative {
+        return None;
+    }
+
+    let soa = Rtype::Soa.to_int() as usize;
+    get_ttl_offsets(buf)?
+        .into_iter()
+        .find(|(section, rtype, _)| *section == 1 && *rtype == soa)
+        .and_then(|(_, _, offset)| {
+            // the minimum field closes the rdata
+            let rdlen = u16::from_be_bytes([buf[offset + 4], buf[offset + 5]]) as usize;
+            let end = offset + 6 + rdlen;
+            if rdlen < 20 || end > buf.len() {
+                return None;
+            }
+            Some(get_ttl(buf, offset).min(get_ttl(buf, end - 4)))
+        })
+}
+
+// count the ttl of every record down by the seconds a reply has been kept
+pub fn age_response(buf: &mut [u8], elapsed: u32) {
+    map_ttls(buf, |ttl| ttl.saturating_sub(elapsed));
+}
+
+// lower the ttl of every record to at most `max_ttl`
+pub fn cap_ttls(buf: &mut [u8], max_ttl: u32) {
+    map_ttls(buf, |ttl| ttl.min(max_ttl));
+}
+
+fn map_ttls<F: Fn(u32) -> u32>(buf: &mut [u8], f: F) {
+    if let Some(offsets) = get_ttl_offsets(buf) {
+        for (_, _, offset) in offsets {
+            let ttl = f(get_ttl(buf, offset));
+            buf[offset..offset + 4].copy_from_slice(&ttl.to_be_bytes());
+        }
+    }
+}
+
+// the same query for another record type, e.g. A records of a name queried for AAAA
+pub fn get_companion_message(origin: &Message<Vec<u8>>, qtype: Rtype) -> Message<Vec<u8>> {
+    let mut msg = MessageBuilder::new_vec();
+    *msg.header_mut() = origin.header();
+
+    let mut msg = msg.question();
+    if let Some(question) = origin.first_question() {
+        msg.push((question.qname(), qtype, question.qclass()))
+            .unwrap();
+    }
+
+    let mut msg = msg.additional();
+    if let Ok(options) = origin.additional() {
+        for record in options.flatten() {
+            if let Ok(Some(option)) = record.into_record::<Opt<&[u8]>>() {
+                msg.push(&option).unwrap();
+            }
+        }
+    }
+
+    let buf = msg.finish();
+    Message::from_octets(buf).unwrap()
+}
+
 pub fn get_response_message<T: AsRecord>(
     id: u16,
     origin: &Message<Vec<u8>>,
@@ -106,12 +494,10 @@ pub fn get_response_message<T: AsRecord>(
     let mut msg = msg.additional();
     let options = origin.additional().unwrap();
     for record in options {
-        let option = record
-            .unwrap()
-            .into_record::<Opt<&[u8]>>()
-            .unwrap()
-            .unwrap();
-        msg.push(&option).unwrap();
+        // only the OPT record is kept, glue and other additional records are dropped
+        if let Ok(Some(option)) = record.and_then(|record| record.into_record::<Opt<&[u8]>>()) {
+            msg.push(&option).unwrap();
+        }
     }
 
     let buf = msg.finish();
@@ -119,3 +505,166 @@ pub fn get_response_message<T: AsRecord>(
 
     msg
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: u16 = 1;
+    const SOA: u16 = 6;
+    const OPT: u16 = 41;
+    const NXDOMAIN: u8 = 3;
+    // the DO bit, in the ttl field of OPT records
+    const OPT_FLAGS: u32 = 0x8000;
+
+    // a reply to `example.com A`, records are added after
+    fn reply(rcode: u8, counts: [u16; 3]) -> Vec<u8> {
+        let mut buf = vec![0x12, 0x34, 0x81, 0x80 | rcode, 0, 1];
+        for count in &counts {
+            buf.extend_from_slice(&count.to_be_bytes());
+        }
+        buf.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        buf
+    }
+
+    // a record whose owner is a compression pointer to the question name
+    fn push_record(buf: &mut Vec<u8>, rtype: u16, ttl: u32, rdata: &[u8]) {
+        buf.extend_from_slice(&[0xc0, 0x0c]);
+        buf.extend_from_slice(&rtype.to_be_bytes());
+        buf.extend_from_slice(&1u16.to_be_bytes());
+        buf.extend_from_slice(&ttl.to_be_bytes());
+        buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        buf.extend_from_slice(rdata);
+    }
+
+    fn push_opt(buf: &mut Vec<u8>) {
+        buf.push(0);
+        buf.extend_from_slice(&OPT.to_be_bytes());
+        buf.extend_from_slice(&1232u16.to_be_bytes());
+        buf.extend_from_slice(&OPT_FLAGS.to_be_bytes());
+        buf.extend_from_slice(&0u16.to_be_bytes());
+    }
+
+    fn get_ttls(buf: &[u8]) -> Vec<u32> {
+        get_ttl_offsets(buf)
+            .unwrap()
+            .into_iter()
+            .map(|(_, _, offset)| get_ttl(buf, offset))
+            .collect()
+    }
+
+    // two answers with compressed owners and an OPT record
+    fn answers() -> Vec<u8> {
+        let mut buf = reply(0, [2, 0, 1]);
+        push_record(&mut buf, A, 300, &[1, 2, 3, 4]);
+        push_record(&mut buf, A, 60, &[5, 6, 7, 8]);
+        push_opt(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn finds_ttls_after_compressed_names() {
+        let buf = answers();
+        let offsets = get_ttl_offsets(&buf).unwrap();
+        assert_eq!(offsets.len(), 2);
+        assert!(offsets
+            .iter()
+            .all(|&(section, rtype, _)| section == 0 && rtype == A as usize));
+        assert_eq!(get_ttls(&buf), vec![300, 60]);
+        assert_eq!(get_min_answer_ttl(&buf), Some(60));
+    }
+
+    #[test]
+    fn no_min_ttl_without_answers() {
+        let mut buf = reply(0, [0, 0, 1]);
+        push_opt(&mut buf);
+        assert_eq!(get_min_answer_ttl(&buf), None);
+    }
+
+    #[test]
+    fn ages_and_caps_ttls_but_not_opt_flags() {
+        let mut buf = answers();
+        let opt_flags = buf.len() - 6;
+
+        age_response(&mut buf, 100);
+        assert_eq!(get_ttls(&buf), vec![200, 0]);
+        assert_eq!(get_ttl(&buf, opt_flags), OPT_FLAGS);
+
+        let mut buf = answers();
+        cap_ttls(&mut buf, 30);
+        assert_eq!(get_ttls(&buf), vec![30, 30]);
+        assert_eq!(get_ttl(&buf, opt_flags), OPT_FLAGS);
+    }
+
+    #[test]
+    fn truncated_replies_have_no_ttls() {
+        let buf = answers();
+        // cut within the rdata, within the fixed fields and within the question
+        for len in &[buf.len() - 13, buf.len() - 20, 20, 12, 5, 0] {
+            let mut truncated = buf[..*len].to_vec();
+            assert_eq!(get_ttl_offsets(&truncated), None);
+            assert_eq!(get_min_answer_ttl(&truncated), None);
+
+            // left as they are
+            age_response(&mut truncated, 100);
+            cap_ttls(&mut truncated, 30);
+            assert_eq!(truncated, &buf[..*len]);
+        }
+    }
+
+    // SOA rdata naming the question twice, then serial, refresh, retry, expire and minimum
+    fn soa(minimum: u32) -> Vec<u8> {
+        let mut rdata = vec![0xc0, 0x0c, 0xc0, 0x0c];
+        for field in &[1, 7200, 3600, 1209600, minimum] {
+            rdata.extend_from_slice(&field.to_be_bytes());
+        }
+        rdata
+    }
+
+    #[test]
+    fn negative_ttl_is_the_lower_of_soa_ttl_and_minimum() {
+        let mut buf = reply(NXDOMAIN, [0, 1, 0]);
+        push_record(&mut buf, SOA, 900, &soa(300));
+        assert_eq!(get_negative_ttl(&buf), Some(300));
+
+        let mut buf = reply(NXDOMAIN, [0, 1, 0]);
+        push_record(&mut buf, SOA, 60, &soa(300));
+        assert_eq!(get_negative_ttl(&buf), Some(60));
+
+        // NODATA, with an OPT record
+        let mut buf = reply(0, [0, 1, 1]);
+        push_record(&mut buf, SOA, 900, &soa(300));
+        push_opt(&mut buf);
+        assert_eq!(get_negative_ttl(&buf), Some(300));
+    }
+
+    #[test]
+    fn no_negative_ttl_without_soa() {
+        let buf = reply(NXDOMAIN, [0, 0, 0]);
+        assert_eq!(get_negative_ttl(&buf), None);
+
+        // another record in authority
+        let mut buf = reply(NXDOMAIN, [0, 1, 0]);
+        push_record(&mut buf, A, 900, &[1, 2, 3, 4]);
+        assert_eq!(get_negative_ttl(&buf), None);
+
+        // a SOA which is too short for its minimum field
+        let mut buf = reply(NXDOMAIN, [0, 1, 0]);
+        push_record(&mut buf, SOA, 900, &soa(300)[..16]);
+        assert_eq!(get_negative_ttl(&buf), None);
+    }
+
+    #[test]
+    fn no_negative_ttl_for_answers() {
+        assert_eq!(get_negative_ttl(&answers()), None);
+    }
+
+    #[test]
+    fn no_negative_ttl_for_truncated_authority() {
+        let mut buf = reply(NXDOMAIN, [0, 1, 0]);
+        push_record(&mut buf, SOA, 900, &soa(300));
+        for len in &[buf.len() - 1, buf.len() - 20, buf.len() - 30, 3, 0] {
+            assert_eq!(get_negative_ttl(&buf[..*len]), None);
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::{fmt, net::IpAddr};
+
+// A set of networks, kept as sorted and merged address ranges so that
+// a lookup is a binary search, even with chnroute-sized lists.
+#[derive(Clone, Default)]
+pub struct CidrSet {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl CidrSet {
+    // `1.0.1.0/24`, `240e::/20` or a single address
+    pub fn insert(&mut self, cidr: &str) -> Result<(), String> {
+        let mut parts = cidr.trim().splitn(2, '/');
+        let ip = parts
+            .next()
+            .unwrap_or_default()
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid network {}", cidr))?;
+        let max_prefix = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix
+                .parse::<u32>()
+                .map_err(|_| format!("invalid prefix in {}", cidr))?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(format!("invalid prefix in {}", cidr));
+        }
+
+        match ip {
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                let start = u32::from(ip) & mask;
+                self.v4.push((start, start | !mask));
+            }
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                let start = u128::from(ip) & mask;
+                self.v6.push((start, start | !mask));
+            }
+        }
+        Ok(())
+    }
+
+    // one network per line, `#` starts a comment
+    pub fn insert_lines(&mut self, text: &str) -> Result<(), String> {
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if !line.is_empty() {
+                self.insert(line)?;
+            }
+        }
+        Ok(())
+    }
+
+    // must be called after inserting
+    pub fn build(&mut self) {
+        merge(&mut self.v4);
+        merge(&mut self.v6);
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(*ip)),
+            IpAddr::V6(ip) => contains(&self.v6, u128::from(*ip)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+}
+
+impl fmt::Debug for CidrSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CidrSet({} networks)", self.len())
+    }
+}
+
+fn merge<T: Ord + Copy>(ranges: &mut Vec<(T, T)>) {
+    ranges.sort();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    // the last range starting at or before the address
+    match ranges.binary_search_by(|(start, _)| start.cmp(&ip)) {
+        Ok(_) => true,
+        Err(0) => false,
+        Err(i) => ranges[i - 1].1 >= ip,
+    }
+}
@@ -1,23 +1,88 @@
+use super::cidr::CidrSet;
 use maxminddb::{geoip2::Country, Reader};
+use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
 use tokio::fs;
 
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GeoIPSettings {
+    // countries reached directly and resolved by home upstreams, `["CN"]` by default
+    pub home_countries: Option<Vec<String>>,
+    // networks taken as home whatever their country, e.g. chnroute
+    pub allow_cidrs: Option<Vec<String>>,
+    pub allow_files: Option<Vec<String>>,
+    // networks never taken as home, checked before anything else
+    pub deny_cidrs: Option<Vec<String>>,
+    pub deny_files: Option<Vec<String>>,
+}
+
 pub struct GeoIP {
     pub reader: Reader<Vec<u8>>,
+    home_countries: Vec<String>,
+    allow: CidrSet,
+    deny: CidrSet,
 }
 
 impl GeoIP {
     pub async fn new() -> GeoIP {
-        match fs::read("data/GeoLite2-Country.mmdb").await {
-            Ok(file) => {
-                let reader = Reader::from_source(file).unwrap();
-                GeoIP { reader }
-            }
+        let reader = match fs::read("data/GeoLite2-Country.mmdb").await {
+            Ok(file) => Reader::from_source(file).unwrap(),
             Err(_) => {
                 panic!("failed to open geolite2 mmdb file");
             }
+        };
+
+        // the settings file is optional
+        let settings = match fs::read_to_string("data/geoip_settings.json").await {
+            Ok(text) => serde_json::from_str::<GeoIPSettings>(text.as_str())
+                .expect("Failed to load geoip settings."),
+            Err(_) => GeoIPSettings::default(),
+        };
+
+        let home_countries = settings
+            .home_countries
+            .unwrap_or_else(|| vec!["CN".to_string()]);
+        let allow = Self::load_cidrs(&settings.allow_cidrs, &settings.allow_files).await;
+        let deny = Self::load_cidrs(&settings.deny_cidrs, &settings.deny_files).await;
+        println!(
+            "[GeoIP] Home countries {:?}, {} allowed and {} denied networks.",
+            home_countries,
+            allow.len(),
+            deny.len()
+        );
+
+        GeoIP {
+            reader,
+            home_countries,
+            allow,
+            deny,
         }
     }
+
+    async fn load_cidrs(cidrs: &Option<Vec<String>>, files: &Option<Vec<String>>) -> CidrSet {
+        let mut set = CidrSet::default();
+        if let Some(cidrs) = cidrs {
+            for cidr in cidrs {
+                if let Err(e) = set.insert(cidr) {
+                    panic!("failed to load geoip settings: {}", e);
+                }
+            }
+        }
+        if let Some(files) = files {
+            for file in files {
+                let text = match fs::read_to_string(file).await {
+                    Ok(text) => text,
+                    Err(e) => panic!("failed to open network list {}: {}", file, e),
+                };
+                if let Err(e) = set.insert_lines(&text) {
+                    panic!("failed to load network list {}: {}", file, e);
+                }
+            }
+        }
+        set.build();
+        set
+    }
+
     pub fn lookup_country_code(&self, ip: &IpAddr) -> &str {
         if let Ok(info) = self.reader.lookup::<Country>(*ip) {
             if let Some(country) = info.country {
@@ -27,4 +92,18 @@ impl GeoIP {
         // println!("warning: error on lookup addr geo info");
         "ERROR"
     }
+
+    // whether an address is reached directly, rather than through the proxy
+    pub fn is_home(&self, ip: &IpAddr) -> bool {
+        if self.deny.contains(ip) {
+            return false;
+        }
+        if self.allow.contains(ip) {
+            return true;
+        }
+        let country_code = self.lookup_country_code(ip);
+        self.home_countries
+            .iter()
+            .any(|home| home.eq_ignore_ascii_case(country_code))
+    }
 }
@@ -1,3 +1,4 @@
+pub mod cidr;
 mod geoip;
 mod passthrough;
 mod proxy;
@@ -76,7 +77,7 @@ impl Router {
                 addr, dst_ip, dst_port, country_code
             );
 
-            if country_code != "CN" {
+            if !self.geoip.is_home(&dst_ip) {
                 let transfer = proxy(socket, dst_ip, dst_port, info_message).map(|r| {
                     if let Err(e) = r {
                         println!("Failed to proxy {}", e);